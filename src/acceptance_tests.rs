@@ -1,17 +1,42 @@
+/// The tests running the `toy-blockchain-cli` binary itself, to check its arguments and its output.
+/// -- NOTE: What the node does is tested in-process, with the harness under `tests/`.
 #[cfg(test)]
 mod tests {
-    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
     use std::string::String;
     use std::thread::sleep;
     use std::time::Duration;
 
-    use assertables::{assert_contains, assert_not_contains};
-    use assertables::{assert_contains_as_result, assert_not_contains_as_result};
+    use assertables::assert_contains;
+    use assertables::assert_contains_as_result;
+
+    use crate::NODE_ADDR_ENV_VAR;
+
+    /// Address of a free local port for the node, so that each test gets its own node,
+    /// instead of sending requests to the other tests start_node
+    fn free_node_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("The OS should give us a free port");
+        listener.local_addr().expect("We just bound it").to_string()
+    }
+
+    /// Polls the node until the account creation is mined, so we get a whole block time to work with it
+    fn wait_until_mined(node: &str, name: &str) {
+        for _ in 0..50 {
+            let balance_output = duct::cmd!("cargo", "run", "balance", name)
+                .env(NODE_ADDR_ENV_VAR, node).read().expect("The balance command should work");
+            if !balance_output.contains("No account found") {
+                return;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        panic!("The account of {} was never created", name);
+    }
 
     #[test]
     fn running_with_start_node_keeps_me_running() {
+        let node = free_node_address();
         let minimum_living_time = 2;
-        let node_res = duct::cmd!("cargo", "run", "start_node").start();
+        let node_res = duct::cmd!("cargo", "run", "start_node", "--listen", &node).start();
         assert!(node_res.is_ok(), "Failed to run: {:?}", node_res);
         if let Ok(node) = node_res {
             sleep(Duration::from_secs(minimum_living_time));
@@ -26,6 +51,33 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn an_idle_node_does_not_burn_the_cpu() {
+        let node = free_node_address();
+        let node_handle = duct::cmd!("cargo", "run", "start_node", "--listen", &node).start()
+            .expect("The start_node command should work");
+        // NOTE: `cargo run` execs the node, so this is the node's own process
+        let pid = node_handle.pids()[0];
+        let cpu_ticks = || {
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).expect("The node should be running");
+            // The fields after the parenthesized command name, utime and stime being the 14th and 15th
+            let fields = stat.rsplit(") ").next().expect("The stat line has a command name").split(' ').collect::<Vec<_>>();
+            fields[11].parse::<u64>().expect("utime is a number") + fields[12].parse::<u64>().expect("stime is a number")
+        };
+        sleep(Duration::from_secs(1));
+
+        let ticks_before = cpu_ticks();
+        sleep(Duration::from_secs(2));
+        let idle_ticks = cpu_ticks() - ticks_before;
+
+        // cleanup
+        assert!(node_handle.kill().is_ok());
+
+        // A busy loop would use about 200 ticks (of 10ms) in those 2 seconds
+        assert!(idle_ticks < 20, "The idle node used {} ticks of cpu", idle_ticks);
+    }
+
     #[test]
     fn when_not_using_the_start_node_command_be_short_lived() {
         // This should be running help
@@ -43,157 +95,58 @@ mod tests {
         }
     }
 
-    // NOTE: HACK: For now, you'll want to run tests using `cargo test -- --test-threads 1`
-    // Because we don't want other tests to send requests to the other tests start_node
-    // TODO Find a better way
     #[test]
-    fn every_n_seconds_start_node_should_create_a_block() {
-        let block_time_diff = 2;
-        let node_res = duct::cmd!("cargo", "run", "start_node", "--block-time", block_time_diff.to_string()).reader();
-        let reader = node_res.unwrap();
-        // reader.kill().unwrap();
-
-        // As inspired by https://stackoverflow.com/a/31577297/7243716
-        let mut buf_reader = BufReader::new(reader);
-        let mut output = String::new();
-
-        // sleep(Duration::from_secs(block_time_diff));
-        // // Throwing away the "listening started, ready to accept\n"
-        // buf_reader.read_line(&mut output).unwrap();
-        // output = String::new();
-
-        sleep(Duration::from_secs(block_time_diff));
-        sleep(Duration::from_secs(block_time_diff));
-        if let Ok(_val) = buf_reader.read_line(&mut output) {
-            assert_contains!(output, "current_block_num: 0");
-         }
-
-        sleep(Duration::from_secs(block_time_diff));
-        if let Ok(_val) = buf_reader.read_line(&mut output) {
-            assert_contains!(output, "current_block_num: 1");
-        }
-
-        sleep(Duration::from_secs(block_time_diff));
-        buf_reader.read_line(&mut output).unwrap();
-        if let Ok(_val) = buf_reader.read_line(&mut output) {
-            assert_contains!(output, "current_block_num: 2");
-        }
-
-
-        fn extract_integer_timestamp(line: &str) -> u64 {
-            line.split(" ").take(1).collect::<String>()
-                .chars().filter(|c| c.is_digit(10))
-                .collect::<String>().parse::<u64>().unwrap()
-        }
-        output.lines().map(extract_integer_timestamp).collect::<Vec<_>>().windows(2).for_each(
-            |pair|
-                assert_eq!(pair[0] + block_time_diff, pair[1])
-        );
-    }
-
-    #[test]
-    fn account_creation_and_balance() {
-        let block_time = 1;
-        let balance: u128 = 1000;
-        let node_res = duct::cmd!("cargo", "run", "start_node", "--block-time", block_time.to_string()).start();
-        let node_handle = node_res.expect("The start_node command should work");
-
-        sleep(Duration::from_secs(block_time));
-        sleep(Duration::from_secs(block_time));
-        let account_creation_output = duct::cmd!("cargo", "run", "create_account", "bob", balance.to_string())
-            .read().expect("The create_account command should work");
-
-        assert_contains!(account_creation_output , "Created account");
-        sleep(Duration::from_secs(block_time));
-
-        let account_creation2_output = duct::cmd!("cargo", "run", "create_account", "bob", balance.to_string())
-            .read().expect("The create_account command should work");
+    fn block_times_can_be_given_in_milliseconds() {
+        let node = free_node_address();
+        let node_handle = duct::cmd!("cargo", "run", "start_node", "--listen", &node, "--block-time", "200ms").start()
+            .expect("The start_node command should work");
+        sleep(Duration::from_millis(1500));
+        let listed_blocks_output = duct::cmd!("cargo", "run", "list_blocks")
+            .env(NODE_ADDR_ENV_VAR, &node).read().expect("The list_blocks command should work");
+        let zero_block_time_output = duct::cmd!("cargo", "run", "start_node", "--block-time", "0s")
+            .stderr_to_stdout().unchecked().read().expect("The start_node command should run");
 
+        // cleanup
         assert!(node_handle.kill().is_ok());
-        assert_contains!(account_creation2_output , "Already existing account");
+
+        // Even with a slow start, a few blocks should have been sealed
+        assert_contains!(listed_blocks_output, "\"current_block_num\":2");
+        assert_contains!(zero_block_time_output, "invalid value '0s' for '--block-time <BLOCK_TIME>'");
     }
 
     #[test]
-    fn transactions() {
-        let block_time = 2;
-        // let balance: u128 = 1000;
-        let node_res = duct::cmd!("cargo", "run", "start_node", "--block-time", block_time.to_string()).start();
-        let node_handle = node_res.expect("The start_node command should work");
-
-        sleep(Duration::from_secs(block_time));
-        sleep(Duration::from_secs(block_time));
-        let initial_accounts = [("alice", 1000), ("bob", 9000)];
-        let initial_account_names = initial_accounts.map(|(name, _)| name);
-        let account_creation_outputs = initial_accounts.map(|account|
-            duct::cmd!("cargo", "run", "create_account", account.0, account.1.to_string())
-                .read().expect("The create_account command should work"));
-        sleep(Duration::from_secs(block_time));
-
-        let transfer_amount = 1000;
-        let transaction_output =
-            duct::cmd!("cargo", "run", "transfer", initial_account_names[0], initial_account_names[1], transfer_amount.to_string())
-                .read().expect("The transfer command should work");
-
-        let balance_output1_before_block =
-            duct::cmd!("cargo", "run", "balance", initial_account_names[0])
-                .read().expect("The balance command should work");
-        let balance_output2_before_block =
-            duct::cmd!("cargo", "run", "balance", initial_account_names[1])
-                .read().expect("The balance command should work");
-        sleep(Duration::from_secs(block_time+1));
-
-        let balance_output1_after_block =
-            duct::cmd!("cargo", "run", "balance", initial_account_names[0])
-                .read().expect("The balance command should work");
-        let balance_output2_after_block =
-            duct::cmd!("cargo", "run", "balance", initial_account_names[1])
-                .read().expect("The balance command should work");
+    fn the_mempool_should_hold_at_least_one_transaction() {
+        let zero_mempool_size_output = duct::cmd!("cargo", "run", "start_node", "--mempool-size", "0")
+            .stderr_to_stdout().unchecked().read().expect("The start_node command should run");
 
-        assert!(node_handle.kill().is_ok());
-        account_creation_outputs.iter().for_each(
-            |output| { assert_contains!(output , "Created account"); }
-        );
-        assert_contains!(transaction_output, &"Will add this transaction in the next block".to_string());
-        assert_contains!(&balance_output1_before_block, &format!(" {}",initial_accounts[0].1));
-        assert_contains!(&balance_output2_before_block, &format!(" {}",initial_accounts[1].1));
-        assert_contains!(&balance_output2_after_block, &format!(" {}",initial_accounts[1].1 + transfer_amount));
-        assert_contains!(&balance_output1_after_block, &format!(" {}",initial_accounts[0].1 - transfer_amount));
+        assert_contains!(zero_mempool_size_output, "invalid value '0' for '--mempool-size <MEMPOOL_SIZE>'");
     }
 
     #[test]
-    //In case the client are blocking in some way, we rather abort the test than wait.
-    #[ntest::timeout(5000)]
-    fn account_creation_and_already_being_created() {
+    fn json_output_gives_typed_answers() {
+        let node = free_node_address();
         let block_time = 1;
-        let balance: u128 = 1000;
-        let node_res = duct::cmd!("cargo", "run", "start_node", "--block-time", block_time.to_string()).start();
-        let node_handle = node_res.expect("The start_node command should work");
-
-        // To be sure that the node is properly started already
+        let node_handle = duct::cmd!("cargo", "run", "start_node", "--listen", &node, "--block-time", block_time.to_string())
+            .start().expect("The start_node command should work");
         sleep(Duration::from_secs(block_time));
-
-        let balance_output = duct::cmd!("cargo", "run", "balance", "bob")
-            .read().expect("The balance command should work");
-
-        let account_creation_output = duct::cmd!("cargo", "run", "create_account", "bob", balance.to_string())
-            .start().expect("The create_account command should work");
-
-        sleep(Duration::from_secs(block_time));
-        if account_creation_output.kill().is_ok() { println!("Should not be long running") }
-
-        let balance_output2 = duct::cmd!("cargo", "run", "balance", "bob")
-            .read().expect("The balance command should work");
-
+        let run_json = |args: &[&str]| -> serde_json::Value {
+            let output = duct::cmd("cargo", ["run", "--quiet", "--", "--output", "json"].iter().chain(args))
+                .env(NODE_ADDR_ENV_VAR, &node).read().expect("The client command should work");
+            serde_json::from_str(&output).expect("The json output should be a single json value")
+        };
+
+        let account_creation = run_json(&["create_account", "bob", "1000"]);
+        wait_until_mined(&node, "bob");
+        let balance = run_json(&["balance", "bob", "--pending"]);
+        let unknown_account = run_json(&["nonce", "alice"]);
+        let tx_status = run_json(&["tx_status", account_creation["Ok"]["AccountCreationAccepted"]["id"].as_str()
+            .expect("The account creation should be accepted with an id")]);
 
         // cleanup
         assert!(node_handle.kill().is_ok());
 
-        //
-        // assertions
-        //
-        assert_contains!(balance_output, "No account found");
-        assert_contains!(balance_output2, &balance.to_string());
-        assert_not_contains!(balance_output2, &"created".to_string());
-        assert_not_contains!(balance_output2, &"Already existing account".to_string());
+        assert_eq!(balance["Ok"]["Balance"], serde_json::json!({ "name": "bob", "balance": 1000, "projected_balance": 1000 }));
+        assert_eq!(unknown_account["Ok"]["AccountNotFound"]["name"], "alice");
+        assert!(tx_status["Ok"]["TransactionStatus"]["status"]["Included"]["block_num"].is_u64(), "Got {}", tx_status);
     }
-}
\ No newline at end of file
+}
@@ -1,150 +1,936 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::time::{Duration, Instant};
 
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Clock, SystemClock};
+use crate::genesis::Genesis;
+use crate::hashing::{hash_of, merkle_root, ZERO_HASH};
+use crate::keys::{parse_public_key, verify_transfer};
+use crate::mempool::{Mempool, PendingTransaction};
+use crate::protocol::{Answer, TransactionStatus};
+use crate::storage::ChainStore;
 use crate::{Transaction, TransactionTransfer};
 
-#[derive(Debug)]
-struct Block {
-    current_block_num: usize,
-    transactions: Vec<Transaction>,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub balance: u64,
+    /// Number of transfers sent from this account, which is also the nonce expected for the next one
+    pub nonce: u64,
+    /// Hex encoded ed25519 key that must sign the transfers sent from this account.
+    /// Without it, the account can only receive tokens.
+    pub public_key: Option<String>,
+}
+
+/// Time between each block, unless the genesis or `start_node --block-time` says otherwise
+pub const DEFAULT_BLOCK_TIME: Duration = Duration::from_secs(10);
+
+/// Longest time between two blocks, so that the next block time always fits in an `Instant`
+pub const MAX_BLOCK_TIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Checks a block time given by the user, from the command line, the genesis or a `NodeBuilder`
+pub fn check_block_time(block_time: Duration) -> Result<(), String> {
+    if block_time.is_zero() {
+        return Err("the block time should be positive".to_string());
+    }
+    if block_time > MAX_BLOCK_TIME {
+        return Err(format!("the block time should be at most {:?}", MAX_BLOCK_TIME));
+    }
+    Ok(())
+}
+
+/// Chain id of the nodes started without a genesis file
+pub const DEFAULT_CHAIN_ID: &str = "toy-blockchain-local";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHeader {
+    /// Identifies which chain the block belongs to, as set by its genesis
+    pub chain_id: String,
+    pub current_block_num: usize,
+    /// Hash of the previous block, or `ZERO_HASH` for the first block
+    pub parent_hash: String,
+    /// Hash of every other field of this header
+    pub hash: String,
+    /// Milliseconds since the UNIX epoch, when the block was sealed
+    pub timestamp: u64,
+    /// Merkle root of the hashes of the block's transactions
+    pub transactions_root: String,
+    /// Hash of all the accounts balances, after applying the block's transactions
+    pub state_root: String,
+}
+
+impl BlockHeader {
+    /// Recomputes the hash from the other fields, to detect any tampering with them
+    pub(crate) fn compute_hash(&self) -> String {
+        hash_of(&(
+            &self.chain_id,
+            self.current_block_num,
+            &self.parent_hash,
+            self.timestamp,
+            &self.transactions_root,
+            &self.state_root,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Block {
+    /// Fills in the header of a block whose transactions were already applied to `accounts`
+    pub(crate) fn seal(
+        chain_id: &str,
+        current_block_num: usize,
+        parent_hash: String,
+        timestamp: u64,
+        transactions: Vec<Transaction>,
+        accounts: &HashMap<String, Account>,
+    ) -> Self {
+        let transactions_root = merkle_root(
+            &transactions.iter().map(Transaction::id).collect::<Vec<_>>());
+        let mut header = BlockHeader {
+            chain_id: chain_id.to_string(),
+            current_block_num,
+            parent_hash,
+            hash: String::new(),
+            timestamp,
+            transactions_root,
+            state_root: state_root(accounts),
+        };
+        header.hash = header.compute_hash();
+        Self { header, transactions }
+    }
+}
+
+/// Hash of the accounts, sorted by name so the result doesn't depend on the HashMap ordering
+pub(crate) fn state_root(accounts: &HashMap<String, Account>) -> String {
+    hash_of(&accounts.iter().collect::<BTreeMap<_, _>>())
+}
+
+/// How many blocks `list_blocks` returns at most, to keep the responses reasonably small
+const MAX_LISTED_BLOCKS: usize = 100;
+
+/// How many history entries `history` returns at most per page
+const MAX_HISTORY_PAGE_SIZE: usize = 100;
+
+/// A transaction that touched an account, as listed by `history`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub block_num: usize,
+    pub transaction_id: String,
+    pub transaction: Transaction,
+}
+
+/// How many rejected transactions we remember the reason of, the oldest ones being forgotten first
+const MAX_REMEMBERED_REJECTIONS: usize = 10_000;
+
+/// Why the most recent transactions were rejected, so clients can look them up by id
+#[derive(Debug, Default)]
+struct Rejections {
+    order: VecDeque<String>,
+    reasons: HashMap<String, String>,
+}
+
+impl Rejections {
+    fn record(&mut self, id: String, reason: String) {
+        if self.reasons.insert(id.clone(), reason).is_none() {
+            self.order.push_back(id);
+        }
+        if self.order.len() > MAX_REMEMBERED_REJECTIONS {
+            let forgotten = self.order.pop_front().expect("We are over the limit, so not empty");
+            self.reasons.remove(&forgotten);
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct BlockChain {
+    chain_id: String,
     node_start_instant: Instant,
     duration_between_blocks: Duration,
     last_mining_time: Instant,
     blocks: Vec::<Block>,
-    accounts: HashMap::<String, u64>,
+    /// Block number of every included transaction, by transaction id
+    included_transactions: HashMap<String, usize>,
+    /// (block number, position in the block) of every transaction touching an account, by account name
+    account_history: HashMap<String, Vec<(usize, usize)>>,
+    rejections: Rejections,
+    accounts: HashMap::<String, Account>,
+    /// Account creations and transfers waiting for the next block
+    mempool: Mempool,
+    /// Where sealed blocks are persisted, if anywhere
+    store: Option<ChainStore>,
+    /// Clients watching the new blocks, with the accounts they watch (all of them if empty)
+    subscribers: Vec<(mpsc::Sender<Answer>, Vec<String>)>,
+    /// Where the block times and timestamps come from
+    clock: Box<dyn Clock>,
 }
 
 impl Default for BlockChain {
     fn default() -> Self {
-        Self::new(10)
+        Self::new(DEFAULT_BLOCK_TIME)
     }
 }
 
 impl BlockChain {
-    pub(crate) fn new(block_time: u64) -> Self {
-        let node_start_instant = Instant::now();
-        let mut last_mining_time = Instant::now();
-        let mut blocks = Vec::new();
-        let duration_between_blocks = Duration::from_secs(block_time);
+    pub fn new(block_time: Duration) -> Self {
+        Self::with_clock(block_time, SystemClock)
+    }
+
+    /// -- NOTE: Tests give it a `ManualClock`, to produce the blocks without waiting for them
+    pub fn with_clock(block_time: Duration, clock: impl Clock + 'static) -> Self {
+        let node_start_instant = clock.now();
+        let last_mining_time = clock.now();
+        let blocks = Vec::new();
+        let duration_between_blocks = block_time;
         let accounts = HashMap::new();
         Self {
+            chain_id: DEFAULT_CHAIN_ID.to_string(),
             node_start_instant,
             duration_between_blocks,
             last_mining_time,
             blocks,
+            included_transactions: HashMap::new(),
+            account_history: HashMap::new(),
+            rejections: Rejections::default(),
             accounts,
+            mempool: Mempool::default(),
+            store: None,
+            subscribers: Vec::new(),
+            clock: Box::new(clock),
         }
     }
+
+    /// Restores the chain persisted in `store`, and keeps persisting the new blocks in it
+    pub fn with_store(block_time: Duration, store: ChainStore) -> Result<Self, String> {
+        Self::new(block_time).restored_from(store)
+    }
+
+    /// Same as `with_store`, for a chain that was given its own clock
+    pub fn restored_from(self, store: ChainStore) -> Result<Self, String> {
+        let mut block_chain = self;
+        let blocks = store.load()?;
+        block_chain.accounts = validate_blocks(&blocks)?;
+        blocks.into_iter().for_each(|block| block_chain.push_block(block));
+        if let Some(first_block) = block_chain.blocks.first() {
+            block_chain.chain_id.clone_from(&first_block.header.chain_id);
+        }
+        log::info!("Restored {} blocks and {} accounts",
+                   block_chain.blocks.len(), block_chain.accounts.len());
+        block_chain.store = Some(store);
+        Ok(block_chain)
+    }
+
+    /// Caps how many transactions can wait for the next block, which should be at least one
+    pub fn limit_mempool(&mut self, max_size: usize) -> Result<(), String> {
+        self.mempool = Mempool::new(max_size)?;
+        Ok(())
+    }
+
+    /// Seals the genesis as block 0, or checks that it is the one we already have
+    pub fn start_from_genesis(&mut self, genesis: &Genesis) -> Result<(), String> {
+        let genesis_block = genesis.block()?;
+        if let Some(first_block) = self.blocks.first() {
+            return if first_block.header.hash == genesis_block.header.hash {
+                Ok(())
+            } else {
+                Err(format!("This chain was started from another genesis than the {} one", genesis.chain_id))
+            };
+        }
+        self.accounts = validate_blocks(std::slice::from_ref(&genesis_block))?;
+        self.chain_id.clone_from(&genesis.chain_id);
+        if let Some(store) = &self.store {
+            store.append(&genesis_block)?;
+        }
+        log::info!("Sealed genesis block {:?}", genesis_block);
+        self.push_block(genesis_block);
+        Ok(())
+    }
+
+    /// Appends an already validated block, indexing its transactions
+    fn push_block(&mut self, block: Block) {
+        let block_num = block.header.current_block_num;
+        for (position, transaction) in block.transactions.iter().enumerate() {
+            self.included_transactions.insert(transaction.id(), block_num);
+            for name in touched_accounts(transaction) {
+                self.account_history.entry(name.clone()).or_default().push((block_num, position));
+            }
+        }
+        self.blocks.push(block);
+    }
+
+    /// Finds a block by number, or by hash
+    fn find_block(&self, block: &str) -> Option<&Block> {
+        match block.parse::<usize>() {
+            Ok(block_num) => self.blocks.get(block_num),
+            Err(_) => self.blocks.iter().find(|candidate| candidate.header.hash == block),
+        }
+    }
+
+    /// Blocks from `from` to `to` included, without going over `MAX_LISTED_BLOCKS` nor the latest block
+    fn list_blocks(&self, from: Option<usize>, to: Option<usize>) -> &[Block] {
+        let from = from.unwrap_or(0).min(self.blocks.len());
+        let to = to.map_or(self.blocks.len(), |to| to.saturating_add(1))
+            .min(self.blocks.len())
+            .min(from + MAX_LISTED_BLOCKS);
+        &self.blocks[from..to.max(from)]
+    }
+
+    /// One page (starting at 1) of the transactions that touched the account, oldest first
+    fn history(&self, name: &str, page: usize, per_page: usize) -> Answer {
+        let Some(history) = self.account_history.get(name) else {
+            return Answer::HistoryNotFound { name: name.to_string() };
+        };
+        let per_page = per_page.clamp(1, MAX_HISTORY_PAGE_SIZE);
+        let entries = history.iter()
+            .skip(page.saturating_sub(1).saturating_mul(per_page))
+            .take(per_page)
+            .map(|&(block_num, position)| {
+                let transaction = self.blocks[block_num].transactions[position].clone();
+                HistoryEntry { block_num, transaction_id: transaction.id(), transaction }
+            })
+            .collect();
+        Answer::History(entries)
+    }
+
+    /// The status of the transaction, with the transaction itself when we still have it
+    fn transaction_status(&self, id: &str) -> Answer {
+        let (status, transaction) = if let Some(&block_num) = self.included_transactions.get(id) {
+            let transaction = self.blocks[block_num].transactions.iter().find(|transaction| transaction.id() == id);
+            (TransactionStatus::Included { block_num }, transaction)
+        } else if let Some(transaction) = self.mempool.get(id) {
+            (TransactionStatus::Pending, Some(transaction))
+        } else if let Some(reason) = self.rejections.reasons.get(id) {
+            (TransactionStatus::Rejected { reason: reason.clone() }, None)
+        } else {
+            (TransactionStatus::Unknown, None)
+        };
+        Answer::TransactionStatus { id: id.to_string(), status, transaction: transaction.cloned() }
+    }
+
+    /// Replays the whole chain from its first block, without trusting the current accounts
+    pub fn validate(&self) -> Result<(), String> {
+        validate_blocks(&self.blocks).map(|_| ())
+    }
+}
+
+/// Accounts whose balance or nonce is changed by the transaction
+fn touched_accounts(transaction: &Transaction) -> Vec<&String> {
+    match transaction {
+        Transaction::CreateAccount { name, .. } => vec![name],
+        Transaction::Transfer(transfer) => vec![&transfer.sender, &transfer.receiver],
+        query => unreachable!("Queries are never included in blocks, got {:?}", query),
+    }
+}
+
+/// Replays `blocks` from the first one, checking every header and transaction on the way.
+/// Returns the resulting balances, or the first invalid block and why it is invalid.
+pub(crate) fn validate_blocks(blocks: &[Block]) -> Result<HashMap<String, Account>, String> {
+    let mut accounts = HashMap::new();
+    let mut parent_hash = ZERO_HASH.to_string();
+    let chain_id = blocks.first().map_or(DEFAULT_CHAIN_ID, |block| &block.header.chain_id);
+    for (block_num, block) in blocks.iter().enumerate() {
+        validate_block(&mut accounts, chain_id, block_num, &parent_hash, block)
+            .map_err(|msg| format!("Block {} is invalid: {}", block_num, msg))?;
+        parent_hash.clone_from(&block.header.hash);
+    }
+    Ok(accounts)
+}
+
+fn validate_block(
+    accounts: &mut HashMap<String, Account>,
+    chain_id: &str,
+    block_num: usize,
+    parent_hash: &str,
+    block: &Block,
+) -> Result<(), String> {
+    let header = &block.header;
+    if header.chain_id != chain_id {
+        return Err(format!("it belongs to the {} chain instead of {}", header.chain_id, chain_id));
+    }
+    if header.current_block_num != block_num {
+        return Err(format!("it is numbered {}", header.current_block_num));
+    }
+    if header.parent_hash != parent_hash {
+        return Err(format!("its parent hash {} should be {}", header.parent_hash, parent_hash));
+    }
+    if header.hash != header.compute_hash() {
+        return Err(format!("its hash {} doesn't match its header", header.hash));
+    }
+    if header.transactions_root != merkle_root(&block.transactions.iter().map(Transaction::id).collect::<Vec<_>>()) {
+        return Err("its transactions root doesn't match its transactions".to_string());
+    }
+    for transaction in &block.transactions {
+        match transaction {
+            Transaction::CreateAccount { name, balance, public_key } => {
+                create_account(accounts, name, *balance, public_key)?;
+            }
+            Transaction::Transfer(transfer) => {
+                can_transfer(accounts, chain_id, transfer)?;
+                transfer_between_accounts(accounts, transfer)?;
+            }
+            query @ (Transaction::Balance { .. } | Transaction::Nonce { .. } | Transaction::Account { .. } | Transaction::TxStatus { .. }
+            | Transaction::GetBlock { .. } | Transaction::LatestBlock | Transaction::ListBlocks { .. }
+            | Transaction::History { .. } | Transaction::Mempool | Transaction::Subscribe { .. }
+            | Transaction::VerifyChain) => {
+                return Err(format!("it contains a query instead of a transaction: {:?}", query));
+            }
+        }
+    }
+    if header.state_root != state_root(accounts) {
+        return Err("its state root doesn't match the replayed balances".to_string());
+    }
+    Ok(())
 }
 
 impl BlockChain {
-    pub(crate) fn try_mining(
-        &mut self,
-        transactions_rx: &mut Receiver<(mpsc::Sender<String>, Transaction)>,
-        transfers: &mut Vec<TransactionTransfer>,
-    ) {
-        let current_time = Instant::now();
-        let mut block = Block {
-            current_block_num: self.blocks.len(),
-            transactions: Vec::<Transaction>::new(),
-        };
-        while let Ok((msg_tx, transaction)) = transactions_rx.try_recv() {
-            msg_tx.send(match transaction {
-                Transaction::Balance { name } => {
-                    let balance = self.accounts.get(&name);
-                    match balance {
-                        Some(val) => format!("Account of {} has a balance of {}", name, val),
-                        None => format!("No account found for {}", name),
+    /// Answers the requests as they come, and seals a block every `duration_between_blocks`.
+    /// -- NOTE: It sleeps on the channel until the next request or block time, so an idle node stays idle.
+    ///          Returns once every sender of `transactions_rx` is gone.
+    pub fn run(&mut self, transactions_rx: Receiver<(mpsc::Sender<Answer>, Transaction)>) {
+        self.run_until(transactions_rx, &AtomicBool::new(false));
+    }
+
+    /// Same as `run`, but also returns once `stopped` is set, as soon as a request or the block time wakes it up
+    pub fn run_until(&mut self, transactions_rx: Receiver<(mpsc::Sender<Answer>, Transaction)>, stopped: &AtomicBool) {
+        while !stopped.load(Ordering::SeqCst) {
+            let until_next_block = self.until_next_block();
+            if until_next_block.is_zero() {
+                self.seal_block();
+                continue;
+            }
+            match transactions_rx.recv_timeout(until_next_block) {
+                Ok((msg_tx, transaction)) => self.answer(msg_tx, transaction),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// Answers a single request right away, for the callers driving the chain themselves with `seal_block`.
+    /// -- NOTE: A `Subscribe` is only useful through `run`, nothing would be left to receive its events here.
+    pub fn request(&mut self, transaction: Transaction) -> Answer {
+        let (msg_tx, msg_rx) = mpsc::channel();
+        self.answer(msg_tx, transaction);
+        msg_rx.recv().expect("Every request gets an answer")
+    }
+
+    fn answer(&mut self, msg_tx: mpsc::Sender<Answer>, transaction: Transaction) {
+        // NOTE: The requester may have given up on its answer, that's no reason to stop the node
+        let _ = msg_tx.send(match transaction {
+            Transaction::Balance { name, pending } => {
+                match self.accounts.get(&name) {
+                    Some(account) => {
+                        let projected_balance = pending.then(|| self.projected_balance(&name, account));
+                        Answer::Balance { balance: account.balance, projected_balance, name }
                     }
+                    None => Answer::AccountNotFound { name },
                 }
-                Transaction::CreateAccount { name, balance } => {
-                    match self.accounts.insert(name.clone(), balance) {
-                        None => {
-                            format!("Created account of {} with balance {}",
-                                    name,
-                                    self.accounts.get(&name).expect("We should have inserted the account now."))
-                        }
-                        Some(balance) => {
-                            format!("Already existing account of {} with balance {}", name, balance)
-                        }
-                    }
+            }
+            Transaction::Account { name } => {
+                match (self.accounts.get(&name), self.pending_account(&name)) {
+                    (Some(account), Some(pending)) => Answer::Account {
+                        balance: account.balance,
+                        projected_balance: self.projected_balance(&name, account),
+                        nonce: pending.nonce,
+                        name,
+                    },
+                    _ => Answer::AccountNotFound { name },
+                }
+            }
+            Transaction::CreateAccount { name, balance, public_key } => {
+                let transaction = Transaction::CreateAccount { name: name.clone(), balance, public_key };
+                let id = transaction.id();
+                match self.can_admit_account_creation(&transaction)
+                    .and_then(|()| self.insert_in_mempool(transaction)) {
+                    Ok(id) => Answer::AccountCreationAccepted { name, balance, id },
+                    Err(msg) => self.reject(id, msg),
+                }
+            }
+            Transaction::TxStatus { id } => {
+                self.transaction_status(&id)
+            }
+            Transaction::GetBlock { block } => {
+                match self.find_block(&block) {
+                    Some(block) => Answer::Block(block.clone()),
+                    None => Answer::BlockNotFound { block },
+                }
+            }
+            Transaction::History { name, page, per_page } => {
+                self.history(&name, page, per_page)
+            }
+            Transaction::LatestBlock => {
+                match self.blocks.last() {
+                    Some(block) => Answer::Block(block.clone()),
+                    None => Answer::NoBlockYet,
+                }
+            }
+            Transaction::ListBlocks { from, to } => {
+                Answer::Blocks(self.list_blocks(from, to).to_vec())
+            }
+            Transaction::Nonce { name } => {
+                match self.pending_account(&name) {
+                    Some(account) => Answer::Nonce { name, nonce: account.nonce },
+                    None => Answer::AccountNotFound { name },
                 }
-                Transaction::Transfer(transaction @ TransactionTransfer { .. }) => {
-                    match can_transfer(&mut self.accounts, &transaction) {
-                        Ok(()) => {
-                            transfers.push(transaction.clone());
-                            format!("Will add this transaction in the next block: {:?}", &transaction)
+            }
+            Transaction::Subscribe { accounts } => {
+                self.subscribers.push((msg_tx.clone(), accounts.clone()));
+                Answer::Subscribed { accounts, latest_block: self.blocks.last().map(|block| block.header.current_block_num) }
+            }
+            Transaction::Mempool => {
+                Answer::Mempool(self.mempool.iter()
+                    .map(|(id, transaction)| PendingTransaction { id: id.clone(), transaction: transaction.clone() })
+                    .collect())
+            }
+            Transaction::VerifyChain => {
+                match self.validate() {
+                    Ok(()) => Answer::ChainValid { blocks: self.blocks.len() },
+                    Err(reason) => Answer::ChainInvalid { reason },
+                }
+            }
+            Transaction::Transfer(transaction @ TransactionTransfer { .. }) => {
+                // NOTE: A duplicate would also fail the nonce check, but this error is clearer.
+                //       It isn't recorded as a rejection, since the same transaction is still pending.
+                let id = Transaction::Transfer(transaction.clone()).id();
+                match self.mempool.check_not_pending(&Transaction::Transfer(transaction.clone())) {
+                    Err(reason) => Answer::Rejected { id, reason },
+                    Ok(_id) => match self.can_admit(&transaction)
+                        .and_then(|()| self.insert_in_mempool(Transaction::Transfer(transaction.clone()))) {
+                        Ok(id) => {
+                            Answer::TransferAccepted { transfer: transaction, id }
                         }
                         Err(msg) => {
-                            msg
+                            self.reject(id, msg)
                         }
                     }
                 }
-            }).expect("msg_tx should be open for one send");
+            }
+        });
+    }
+
+    /// How long until the next block is due, according to our clock
+    /// -- NOTE: A block time too far in the future for an `Instant` never comes.
+    pub fn until_next_block(&self) -> Duration {
+        self.last_mining_time.checked_add(self.duration_between_blocks)
+            .map_or(Duration::MAX, |next_block_time| next_block_time.saturating_duration_since(self.clock.now()))
+    }
+
+    /// Seals the pending transactions that are still valid in a new block, right now, and returns it.
+    /// The next block is then due a whole block time later.
+    pub fn seal_block(&mut self) -> &Block {
+        let next_block_time = self.last_mining_time.checked_add(self.duration_between_blocks);
+        self.mine_block();
+        // NOTE: Scheduling from the planned time rather than from now keeps the blocks from drifting,
+        //       unless we are early, or so late that we'd have to catch up with several blocks at once.
+        let now = self.clock.now();
+        let late_by = next_block_time
+            .and_then(|next_block_time| Some((next_block_time, now.checked_duration_since(next_block_time)?)));
+        self.last_mining_time = match late_by {
+            Some((next_block_time, late_by)) if late_by < self.duration_between_blocks => next_block_time,
+            _ => now,
+        };
+        self.blocks.last().expect("We just sealed it")
+    }
+
+    fn mine_block(&mut self) {
+        let mut transactions = Vec::new();
+        let pending_ids = self.mempool.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+        for id in pending_ids {
+            // NOTE: Every pending transaction leaves the mempool, either included in this block,
+            //       or dropped because it became invalid since we accepted it.
+            let transaction = self.mempool.remove(&id).expect("We just listed the pending ids");
+            let applied = match &transaction {
+                Transaction::CreateAccount { name, balance, public_key } => {
+                    create_account(&mut self.accounts, name, *balance, public_key)
+                }
+                Transaction::Transfer(transfer) => {
+                    can_transfer(&self.accounts, &self.chain_id, transfer)
+                        .and_then(|()| transfer_between_accounts(&mut self.accounts, transfer))
+                }
+                query => unreachable!("Queries are never pending, got {:?}", query),
+            };
+            match applied {
+                Ok(()) => transactions.push(transaction),
+                Err(msg) => {
+                    log::info!("Dropped transaction {}: {}", id, msg);
+                    self.rejections.record(id, msg);
+                }
+            }
         }
-        if current_time.duration_since(self.last_mining_time) > self.duration_between_blocks {
-            transfers.iter().for_each(|transaction| {
-                self.transfer(&mut block, &transaction);
-            });
-            self.blocks.push(block);
-            println!("{:.0?}: created block {:?}",
-                     current_time.duration_since(self.node_start_instant),
-                     self.blocks.last().expect("Just placed it in")
-            );
-            self.last_mining_time = Instant::now();
+        let parent_hash = self.blocks.last()
+            .map_or_else(|| ZERO_HASH.to_string(), |parent| parent.header.hash.clone());
+        let block = Block::seal(&self.chain_id, self.blocks.len(), parent_hash, self.clock.now_millis(),
+                                transactions, &self.accounts);
+        if let Some(store) = &self.store {
+            store.append(&block).expect("We should be able to persist the sealed block");
         }
+        self.push_block(block);
+        let block = self.blocks.last().expect("Just placed it in");
+        log::info!("{:.0?}: created block {} with {} transactions",
+                   self.clock.now().duration_since(self.node_start_instant),
+                   block.header.current_block_num, block.transactions.len());
+        log::debug!("{:?}", block);
+        self.notify_subscribers();
     }
 
-    fn transfer(&mut self, block: &mut Block, transaction: &TransactionTransfer) -> String {
-        if let Err(msg) = can_transfer(&self.accounts, &transaction) {
-            return msg;
-        }
-        if let Err(msg) = transfer_between_accounts(&mut self.accounts, &transaction) {
-            msg
-        } else {
-            block.transactions.push(Transaction::Transfer(transaction.clone()));
-            format!("Successfully transferred {} from {} to {}", transaction.balance, transaction.sender, transaction.receiver)
+    /// Streams the last block, and the accounts it changed, to the clients watching them.
+    /// The ones that went away are forgotten.
+    fn notify_subscribers(&mut self) {
+        let block = self.blocks.last().expect("We only notify about sealed blocks");
+        let mut changed_accounts = block.transactions.iter().flat_map(touched_accounts).collect::<Vec<_>>();
+        changed_accounts.sort();
+        changed_accounts.dedup();
+        let accounts = &self.accounts;
+        self.subscribers.retain(|(subscriber, watched_accounts)| {
+            subscriber.send(Answer::BlockSealed(block.clone())).is_ok()
+                && changed_accounts.iter()
+                .filter(|name| watched_accounts.is_empty() || watched_accounts.contains(name))
+                .all(|&name| {
+                    let account = &accounts[name];
+                    subscriber.send(Answer::AccountChanged {
+                        name: name.clone(),
+                        balance: account.balance,
+                        nonce: account.nonce,
+                        block_num: block.header.current_block_num,
+                    }).is_ok()
+                })
+        });
+    }
+
+    /// Queues an admitted transaction for the next block, returning its id.
+    /// The transaction evicted to make room for it, if any, is then reported as rejected by `tx_status`.
+    fn insert_in_mempool(&mut self, transaction: Transaction) -> Result<String, String> {
+        let (id, evicted_id) = self.mempool.insert(transaction)?;
+        if let Some(evicted_id) = evicted_id {
+            self.rejections.record(evicted_id, "evicted from a full mempool".to_string());
         }
+        Ok(id)
     }
-}
 
-fn can_transfer(accounts: &HashMap<String, u64>, transfer: &TransactionTransfer) -> Result<(), String> {
-    if let Some(sender_balance) = accounts.get(&transfer.sender) {
-        if *sender_balance >= transfer.balance {
-            if accounts.contains_key(&transfer.receiver) {
-                Ok(())
-            } else {
-                Err(format!("Missing receiver's account: {}: cannot send {} to {}",
-                            &transfer.receiver, &transfer.sender, &transfer.balance))
+    /// Remembers why the transaction was rejected, and returns that reason for the client
+    fn reject(&mut self, id: String, reason: String) -> Answer {
+        self.rejections.record(id.clone(), reason.clone());
+        Answer::Rejected { id, reason }
+    }
+
+    /// The account as it will be once its pending outgoing transfers are mined.
+    /// The balance of `account` once its pending transfers, incoming and outgoing, are mined
+    fn projected_balance(&self, name: &str, account: &Account) -> u64 {
+        let (outgoing, _count) = self.mempool.pending_outgoing(name, account.nonce);
+        let incoming = self.mempool.pending_incoming(name);
+        account.balance.saturating_add(incoming).saturating_sub(outgoing)
+    }
+
+    /// NOTE: Pending incoming transfers are not counted, as the sender could still have them dropped.
+    fn pending_account(&self, name: &str) -> Option<Account> {
+        let mut account = self.accounts.get(name)?.clone();
+        let (outgoing, count) = self.mempool.pending_outgoing(name, account.nonce);
+        account.balance = account.balance.saturating_sub(outgoing);
+        account.nonce += count;
+        Some(account)
+    }
+
+    /// Checks a new transfer against the confirmed balances, minus what the sender already has pending.
+    /// The receiver can be an account whose creation is pending, as it will be created first.
+    fn can_admit(&self, transfer: &TransactionTransfer) -> Result<(), String> {
+        match self.pending_account(&transfer.sender) {
+            Some(sender) => {
+                let receiver_balance = self.accounts.get(&transfer.receiver).map(|receiver| receiver.balance)
+                    .or_else(|| self.mempool.pending_account_creation_balance(&transfer.receiver));
+                can_transfer_from(&sender, receiver_balance, &self.chain_id, transfer)
             }
-        } else {
-            Err(format!("Insufficient funds in {}'s account: cannot send {} to {}",
-                        &transfer.sender, &transfer.balance, &transfer.receiver))
+            None => can_transfer(&self.accounts, &self.chain_id, transfer),
+        }
+    }
+
+    fn can_admit_account_creation(&self, transaction: &Transaction) -> Result<(), String> {
+        let Transaction::CreateAccount { name, public_key, .. } = transaction else {
+            unreachable!("Only called for account creations")
+        };
+        if let Some(account) = self.accounts.get(name) {
+            return Err(format!("Already existing account of {} with balance {}", name, account.balance));
+        }
+        self.mempool.check_not_pending(transaction)?;
+        if self.mempool.is_account_creation_pending(name) {
+            return Err(format!("Already pending creation of the account of {}", name));
+        }
+        if let Some(public_key) = public_key {
+            parse_public_key(public_key)?;
         }
+        Ok(())
+    }
+}
+
+/// Inserts a new account, refusing to overwrite an existing one
+pub(crate) fn create_account(
+    accounts: &mut HashMap<String, Account>,
+    name: &str,
+    balance: u64,
+    public_key: &Option<String>,
+) -> Result<(), String> {
+    if let Some(account) = accounts.get(name) {
+        // NOTE: We don't overwrite it, otherwise replaying the blocks would not give the same balances
+        return Err(format!("Already existing account of {} with balance {}", name, account.balance));
+    }
+    if let Some(public_key) = public_key {
+        parse_public_key(public_key)?;
+    }
+    accounts.insert(name.to_string(), Account { balance, nonce: 0, public_key: public_key.clone() });
+    Ok(())
+}
+
+fn can_transfer(accounts: &HashMap<String, Account>, chain_id: &str, transfer: &TransactionTransfer) -> Result<(), String> {
+    if let Some(sender) = accounts.get(&transfer.sender) {
+        can_transfer_from(sender, accounts.get(&transfer.receiver).map(|receiver| receiver.balance), chain_id, transfer)
     } else {
         Err(format!("Missing sender's account: {}: cannot send {} to {}",
                     &transfer.sender, &transfer.balance, &transfer.receiver))
     }
 }
 
-fn transfer_between_accounts(accounts: &mut HashMap<String, u64>, t: &TransactionTransfer) -> Result<(), String> {
-    if let Some(sender_balance) = accounts.get_mut(&t.sender) {
-        if *sender_balance >= t.balance {
+/// Same rules as `can_transfer`, once we know what the sender's account looks like,
+/// and the balance of the receiver's one (`None` if it doesn't exist)
+fn can_transfer_from(
+    sender: &Account,
+    receiver_balance: Option<u64>,
+    chain_id: &str,
+    transfer: &TransactionTransfer,
+) -> Result<(), String> {
+    match &sender.public_key {
+        Some(public_key) => verify_transfer(public_key, chain_id, transfer)?,
+        None => return Err(format!("{}'s account has no public key, so it cannot send {} to {}",
+                                   &transfer.sender, &transfer.balance, &transfer.receiver)),
+    }
+    if transfer.nonce != sender.nonce {
+        return Err(format!("Out of order nonce {} for {}'s account, expected {}: cannot send {} to {}",
+                           &transfer.nonce, &transfer.sender, &sender.nonce, &transfer.balance, &transfer.receiver));
+    }
+    if sender.balance >= transfer.balance {
+        match receiver_balance {
+            // NOTE: Sending to oneself leaves the balance as it is, it can't overflow
+            Some(receiver_balance) if transfer.sender != transfer.receiver
+                && receiver_balance.checked_add(transfer.balance).is_none() =>
+                Err(format!("{}'s balance would overflow: cannot receive {} from {}",
+                            &transfer.receiver, &transfer.balance, &transfer.sender)),
+            Some(_) => Ok(()),
+            None => Err(format!("Missing receiver's account: {}: cannot send {} to {}",
+                                &transfer.receiver, &transfer.sender, &transfer.balance)),
+        }
+    } else {
+        Err(format!("Insufficient funds in {}'s account: cannot send {} to {}",
+                    &transfer.sender, &transfer.balance, &transfer.receiver))
+    }
+}
+
+fn transfer_between_accounts(accounts: &mut HashMap<String, Account>, t: &TransactionTransfer) -> Result<(), String> {
+    if let Some(sender) = accounts.get_mut(&t.sender) {
+        if sender.balance >= t.balance {
             // NOTE In a real system, we would use atomic operations/transaction
-            *sender_balance -= t.balance;
-            if let Some(receiver_balance) = accounts.get_mut(&t.receiver) {
-                *receiver_balance += t.balance;
-                return Ok(());
-            } else {
-                *accounts.get_mut(&t.sender).expect("It existed a few statement ago") += t.balance;
+            sender.balance -= t.balance;
+            sender.nonce += 1;
+            if let Some(receiver) = accounts.get_mut(&t.receiver) {
+                if let Some(balance) = receiver.balance.checked_add(t.balance) {
+                    receiver.balance = balance;
+                    return Ok(());
+                }
             }
+            let sender = accounts.get_mut(&t.sender).expect("It existed a few statement ago");
+            sender.balance += t.balance;
+            sender.nonce -= 1;
         }
     }
     Err(format!("Failed to transfer {} from {} to {}", t.balance, t.sender, t.receiver))
 }
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const BLOCK_TIME: Duration = Duration::from_secs(10);
+    const START_MILLIS: u64 = 1_700_000_000_000;
+
+    fn block_chain() -> (BlockChain, ManualClock) {
+        let clock = ManualClock::new(START_MILLIS);
+        (BlockChain::with_clock(BLOCK_TIME, clock.clone()), clock)
+    }
+
+    fn ask(block_chain: &mut BlockChain, transaction: Transaction) -> Answer {
+        let (msg_tx, msg_rx) = mpsc::channel();
+        block_chain.answer(msg_tx, transaction);
+        msg_rx.recv().expect("Every request is answered")
+    }
+
+    fn signed_transfer(signing_key: &SigningKey, sender: &str, receiver: &str, balance: u64, nonce: u64) -> Transaction {
+        Transaction::Transfer(TransactionTransfer::signed(signing_key, DEFAULT_CHAIN_ID, sender, receiver, balance, nonce))
+    }
+
+    /// Alice (with a balance of 5) can send to bob, who is only `headroom` tokens away from `u64::MAX`
+    fn nearly_full_receiver(headroom: u64) -> (BlockChain, SigningKey) {
+        let (mut block_chain, _clock) = block_chain();
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = hex::encode(signing_key.verifying_key().to_bytes());
+        ask(&mut block_chain, Transaction::CreateAccount { name: "bob".to_string(), balance: u64::MAX - headroom, public_key: None });
+        ask(&mut block_chain, Transaction::CreateAccount { name: "alice".to_string(), balance: 5, public_key: Some(public_key) });
+        block_chain.seal_block();
+        (block_chain, signing_key)
+    }
+
+    #[test]
+    fn blocks_are_due_every_block_time_of_the_clock() {
+        let (mut block_chain, clock) = block_chain();
+
+        assert_eq!(block_chain.until_next_block(), BLOCK_TIME);
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(block_chain.until_next_block(), Duration::from_secs(6));
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(block_chain.until_next_block(), Duration::ZERO);
+
+        let block = block_chain.seal_block();
+        assert_eq!(block.header.current_block_num, 0);
+        assert_eq!(block.header.timestamp, START_MILLIS + 10_000);
+        assert_eq!(block_chain.until_next_block(), BLOCK_TIME);
+    }
+
+    #[test]
+    fn pending_transactions_are_included_in_the_sealed_block() {
+        let (mut block_chain, _clock) = block_chain();
+        let accepted = ask(&mut block_chain, Transaction::CreateAccount {
+            name: "bob".to_string(),
+            balance: 1000,
+            public_key: None,
+        });
+        let balance_before = ask(&mut block_chain, Transaction::Balance { name: "bob".to_string(), pending: false });
+
+        let block = block_chain.seal_block().clone();
+        let balance_after = ask(&mut block_chain, Transaction::Balance { name: "bob".to_string(), pending: false });
+
+        assert!(matches!(accepted, Answer::AccountCreationAccepted { .. }), "Got {:?}", accepted);
+        assert!(matches!(balance_before, Answer::AccountNotFound { .. }), "Got {:?}", balance_before);
+        assert_eq!(block.transactions.len(), 1);
+        assert!(matches!(balance_after, Answer::Balance { balance: 1000, .. }), "Got {:?}", balance_after);
+    }
+
+    #[test]
+    fn a_late_block_does_not_delay_the_next_ones() {
+        let (mut block_chain, clock) = block_chain();
+
+        clock.advance(Duration::from_secs(13));
+        block_chain.seal_block();
+        assert_eq!(block_chain.until_next_block(), Duration::from_secs(7));
+
+        // Too late to keep up, we don't seal several blocks in a row to catch up
+        clock.advance(Duration::from_secs(35));
+        block_chain.seal_block();
+        assert_eq!(block_chain.until_next_block(), BLOCK_TIME);
+    }
+
+    #[test]
+    fn projected_balances_saturate_instead_of_overflowing() {
+        let (mut block_chain, signing_key) = nearly_full_receiver(1);
+        let transfers = [0, 1].map(|nonce| ask(&mut block_chain, signed_transfer(&signing_key, "alice", "bob", 1, nonce)));
+
+        let balance = ask(&mut block_chain, Transaction::Balance { name: "bob".to_string(), pending: true });
+
+        assert!(transfers.iter().all(|answer| matches!(answer, Answer::TransferAccepted { .. })), "Got {:?}", transfers);
+        assert!(matches!(balance, Answer::Balance { projected_balance: Some(u64::MAX), .. }), "Got {:?}", balance);
+    }
+
+    #[test]
+    fn accounts_are_read_with_their_pending_transfers() {
+        let (mut block_chain, signing_key) = nearly_full_receiver(10);
+        ask(&mut block_chain, signed_transfer(&signing_key, "alice", "bob", 2, 0));
+
+        let sender = ask(&mut block_chain, Transaction::Account { name: "alice".to_string() });
+        let missing = ask(&mut block_chain, Transaction::Account { name: "carol".to_string() });
+
+        assert!(matches!(sender, Answer::Account { balance: 5, projected_balance: 3, nonce: 1, .. }), "Got {:?}", sender);
+        assert!(matches!(missing, Answer::AccountNotFound { .. }), "Got {:?}", missing);
+    }
+
+    #[test]
+    fn transfers_overflowing_the_receiver_are_rejected() {
+        let (mut block_chain, signing_key) = nearly_full_receiver(1);
+        let overflowing_transfer = ask(&mut block_chain, signed_transfer(&signing_key, "alice", "bob", 2, 0));
+        let transfers = [0, 1].map(|nonce| ask(&mut block_chain, signed_transfer(&signing_key, "alice", "bob", 1, nonce)));
+
+        // Each one fits on its own, only the second one overflows once the first is mined
+        let block = block_chain.seal_block().clone();
+        let second_transfer_status = match &transfers[1] {
+            Answer::TransferAccepted { id, .. } => ask(&mut block_chain, Transaction::TxStatus { id: id.clone() }),
+            answer => panic!("The second transfer should have been accepted, got {:?}", answer),
+        };
+
+        assert!(matches!(&overflowing_transfer, Answer::Rejected { reason, .. } if reason.contains("would overflow")),
+                "Got {:?}", overflowing_transfer);
+        assert_eq!(block.transactions.len(), 1);
+        assert!(matches!(&second_transfer_status, Answer::TransactionStatus { status: TransactionStatus::Rejected { reason }, .. }
+                         if reason.contains("would overflow")), "Got {:?}", second_transfer_status);
+        assert_eq!(block_chain.accounts["bob"].balance, u64::MAX);
+        assert!(block_chain.validate().is_ok());
+    }
+
+    #[test]
+    fn an_overflowing_transfer_leaves_the_accounts_untouched() {
+        let (block_chain, signing_key) = nearly_full_receiver(0);
+        let mut accounts = block_chain.accounts.clone();
+        let Transaction::Transfer(transfer) = signed_transfer(&signing_key, "alice", "bob", 1, 0) else {
+            unreachable!("We just signed a transfer")
+        };
+
+        let transferred = transfer_between_accounts(&mut accounts, &transfer);
+
+        assert!(transferred.is_err());
+        assert_eq!((accounts["alice"].balance, accounts["alice"].nonce), (5, 0));
+        assert_eq!(accounts["bob"].balance, u64::MAX);
+    }
+
+    #[test]
+    fn transactions_evicted_from_a_full_mempool_are_rejected() {
+        let (mut block_chain, _clock) = block_chain();
+        block_chain.limit_mempool(1).expect("A mempool of 1 is valid");
+        let create_account = |name: &str| Transaction::CreateAccount { name: name.to_string(), balance: 1000, public_key: None };
+        let evicted = ask(&mut block_chain, create_account("alice"));
+        let kept = ask(&mut block_chain, create_account("bob"));
+
+        let statuses = [evicted, kept].map(|answer| match answer {
+            Answer::AccountCreationAccepted { id, .. } => match ask(&mut block_chain, Transaction::TxStatus { id }) {
+                Answer::TransactionStatus { status, .. } => status,
+                answer => panic!("Expected a status, got {:?}", answer),
+            },
+            answer => panic!("The account creation should have been accepted, got {:?}", answer),
+        });
+
+        assert!(matches!(&statuses[0], TransactionStatus::Rejected { reason } if reason == "evicted from a full mempool"),
+                "Got {:?}", statuses[0]);
+        assert!(matches!(statuses[1], TransactionStatus::Pending), "Got {:?}", statuses[1]);
+    }
+
+    #[test]
+    fn transfers_signed_for_another_chain_are_rejected() {
+        let (mut block_chain, signing_key) = nearly_full_receiver(5);
+        let transfer = TransactionTransfer::signed(&signing_key, "another-chain", "alice", "bob", 1, 0);
+
+        let answer = ask(&mut block_chain, Transaction::Transfer(transfer));
+
+        assert!(matches!(&answer, Answer::Rejected { reason, .. } if reason.starts_with("Invalid signature")), "Got {:?}", answer);
+    }
+
+    #[test]
+    fn a_block_time_beyond_any_instant_never_comes() {
+        let mut block_chain = BlockChain::with_clock(Duration::MAX, ManualClock::new(START_MILLIS));
+
+        assert_eq!(block_chain.until_next_block(), Duration::MAX);
+        block_chain.seal_block();
+        assert_eq!(block_chain.until_next_block(), Duration::MAX);
+    }
+}
@@ -0,0 +1,154 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ed25519_dalek::SigningKey;
+use interprocess::local_socket::{GenericFilePath, prelude::*};
+
+use crate::block_chain::DEFAULT_CHAIN_ID;
+use crate::protocol::{self, Answer, ErrorCode, ProtocolError, Request, Response};
+use crate::{Commands, TransactionTransfer};
+
+/// Where a node accepts the client commands
+#[derive(Debug, Clone)]
+pub enum NodeAddress {
+    Tcp(String),
+    LocalSocket(PathBuf),
+}
+
+/// How long the client waits on the node by default, before giving up on its request
+pub const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends commands to a node, one connection per command, and returns its typed answers
+#[derive(Debug, Clone)]
+pub struct NodeClient {
+    address: NodeAddress,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl NodeClient {
+    pub fn new(address: NodeAddress) -> Self {
+        Self { address, read_timeout: Some(DEFAULT_CLIENT_TIMEOUT), write_timeout: Some(DEFAULT_CLIENT_TIMEOUT) }
+    }
+
+    /// How long to wait for the node answer, `None` waiting forever (default 10s).
+    /// -- NOTE: `watch` never times out its reads, the blocks can be far apart.
+    pub fn read_timeout(mut self, read_timeout: Option<Duration>) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// How long to wait for the node to take the request, `None` waiting forever (default 10s)
+    pub fn write_timeout(mut self, write_timeout: Option<Duration>) -> Self {
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    /// Sends the command and waits for the node answer.
+    /// -- NOTE: A refusal from the node (like an unknown account) is still an `Answer`,
+    ///          the errors are for the requests that could not be processed at all.
+    pub fn request(&self, command: &Commands) -> Result<Answer, ProtocolError> {
+        let mut reader = send(command, self.connect(self.read_timeout)?)?;
+        read_answer(&mut reader)?.ok_or_else(|| ProtocolError::new(ErrorCode::NodeUnreachable,
+                                                                     "Could not read from server sending the command"))
+    }
+
+    /// Signs the transfer with the sender's key for the node's chain, and sends it like the `transfer` command does
+    pub fn transfer(&self, signing_key: &SigningKey, sender: &str, receiver: &str, balance: u64, nonce: u64)
+                    -> Result<Answer, ProtocolError> {
+        let chain_id = self.chain_id()?;
+        self.request(&TransactionTransfer::signed(signing_key, &chain_id, sender, receiver, balance, nonce).into())
+    }
+
+    /// The id of the chain the node is on, as recorded in its latest block.
+    /// -- NOTE: A node without any block yet was not started from a genesis, so it is on the default chain.
+    pub fn chain_id(&self) -> Result<String, ProtocolError> {
+        match self.request(&Commands::LatestBlock)? {
+            Answer::Block(block) => Ok(block.header.chain_id),
+            Answer::NoBlockYet => Ok(DEFAULT_CHAIN_ID.to_string()),
+            answer => Err(ProtocolError::new(ErrorCode::NodeUnreachable,
+                                             format!("Unexpected answer to the latest block: {}", answer))),
+        }
+    }
+
+    /// Sends a `watch` command, and hands every streamed answer to `on_answer`, until the node goes away
+    pub fn watch<F: FnMut(Answer)>(&self, command: &Commands, mut on_answer: F) -> Result<(), ProtocolError> {
+        let mut reader = send(command, self.connect(None)?)?;
+        while let Some(answer) = read_answer(&mut reader)? {
+            on_answer(answer);
+        }
+        Ok(())
+    }
+
+    fn connect(&self, read_timeout: Option<Duration>) -> Result<Box<dyn Connection>, ProtocolError> {
+        let unreachable = |_| ProtocolError::new(ErrorCode::NodeUnreachable, "Could not connect to server");
+        Ok(match &self.address {
+            NodeAddress::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).map_err(unreachable)?;
+                stream.set_read_timeout(read_timeout)
+                    .and_then(|_| stream.set_write_timeout(self.write_timeout))
+                    .map_err(unreachable)?;
+                Box::new(stream)
+            }
+            NodeAddress::LocalSocket(path) => Box::new(path.as_path().to_fs_name::<GenericFilePath>()
+                .and_then(LocalSocketStream::connect)
+                .and_then(|stream| local_stream_with_timeouts(stream, read_timeout, self.write_timeout))
+                .map_err(unreachable)?),
+        })
+    }
+}
+
+/// The local socket streams don't expose timeouts, but on unix they are plain unix streams underneath
+#[cfg(unix)]
+pub(crate) fn local_stream_with_timeouts(
+    stream: LocalSocketStream,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+) -> std::io::Result<std::os::unix::net::UnixStream> {
+    let LocalSocketStream::UdSocket(stream) = stream;
+    let stream = std::os::unix::net::UnixStream::from(std::os::fd::OwnedFd::from(stream));
+    stream.set_read_timeout(read_timeout)?;
+    stream.set_write_timeout(write_timeout)?;
+    Ok(stream)
+}
+
+/// -- NOTE: Windows named pipes have no timeouts, a silent peer only holds its own thread.
+#[cfg(not(unix))]
+pub(crate) fn local_stream_with_timeouts(
+    stream: LocalSocketStream,
+    _read_timeout: Option<Duration>,
+    _write_timeout: Option<Duration>,
+) -> std::io::Result<LocalSocketStream> {
+    Ok(stream)
+}
+
+/// Either transport to the node
+trait Connection: Read + Write {}
+
+impl<S: Read + Write> Connection for S {}
+
+/// Sends the command as a json line, returning the connection to read the answers from
+fn send(command: &Commands, mut stream: Box<dyn Connection>) -> Result<BufReader<Box<dyn Connection>>, ProtocolError> {
+    // serde::json : Not as small over-the-wire as binary representation, but easier to debug
+    let request = serde_json::to_string(&Request { command: command.clone() })
+        .expect("The command should be well formed already") + "\n";
+    stream.write_all(request.as_bytes())
+        .map_err(|_| ProtocolError::new(ErrorCode::NodeUnreachable, "Could not write to server after initial connection"))?;
+    Ok(BufReader::new(stream))
+}
+
+/// Reads the next line answered by the node, or `None` once it closed the connection
+fn read_answer<R: BufRead>(reader: &mut R) -> Result<Option<Answer>, ProtocolError> {
+    let mut buf = String::new();
+    let read = reader.read_line(&mut buf)
+        .map_err(|_| ProtocolError::new(ErrorCode::NodeUnreachable, "Could not read from server sending the command"))?;
+    if read == 0 {
+        return Ok(None);
+    }
+    match protocol::parse_response(&buf)? {
+        Response::Ok(answer) => Ok(Some(answer)),
+        Response::Error(error) => Err(error),
+    }
+}
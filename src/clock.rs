@@ -0,0 +1,71 @@
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the `BlockChain` reads the time from, so that tests can control it
+pub trait Clock: Debug + Send {
+    /// Monotonic time, to schedule the blocks
+    fn now(&self) -> Instant;
+    /// Milliseconds since the UNIX epoch, recorded in the block headers
+    fn now_millis(&self) -> u64;
+}
+
+impl<C: Clock + ?Sized> Clock for Box<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn now_millis(&self) -> u64 {
+        (**self).now_millis()
+    }
+}
+
+/// The real time, used by the running nodes
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_millis(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH)
+            .expect("The system clock should be after the UNIX epoch")
+            .as_millis() as u64
+    }
+}
+
+/// A clock that only moves when told to, so that block production can be tested without sleeping.
+/// Its clones share the same time, one can be given to the `BlockChain` and the other one advanced.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    start_millis: u64,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    /// Starts at `start_millis` since the UNIX epoch
+    pub fn new(start_millis: u64) -> Self {
+        Self { start: Instant::now(), start_millis, elapsed: Arc::new(Mutex::new(Duration::ZERO)) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().expect("The clock lock is never poisoned") += duration;
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().expect("The clock lock is never poisoned")
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn now_millis(&self) -> u64 {
+        self.start_millis + self.elapsed().as_millis() as u64
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::block_chain::{check_block_time, create_account, Account, Block};
+use crate::hashing::ZERO_HASH;
+use crate::{parse_block_time, Transaction};
+
+/// Initial state of a chain, shared between teammates so that every local node starts the same way.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Genesis {
+    /// Identifies the chain, and is recorded in every block header
+    pub chain_id: String,
+    /// Time between each block, unless overridden by `start_node --block-time`.
+    /// Written like `--block-time`, as `"500ms"`, `"2s"` or `"1m"`, a plain number being in seconds.
+    #[serde(default, serialize_with = "serialize_block_time", deserialize_with = "deserialize_block_time")]
+    pub block_time: Option<Duration>,
+    /// Milliseconds since the UNIX epoch recorded in the genesis block.
+    /// It is fixed (instead of the node start time) so that the genesis block hash is reproducible.
+    #[serde(default)]
+    pub timestamp: u64,
+    #[serde(default)]
+    pub accounts: Vec<GenesisAccount>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisAccount {
+    /// Name of the account holder
+    pub name: String,
+    /// starting balance on the account
+    pub balance: u64,
+    /// Hex encoded public key allowed to send from this account, as given by `keygen`
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+impl Genesis {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read the genesis file {}: {}", path.display(), e))?;
+        let genesis: Self = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid genesis file {}: {}", path.display(), e))?;
+        genesis.check()?;
+        Ok(genesis)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.chain_id.is_empty() {
+            return Err("The genesis chain_id should not be empty".to_string());
+        }
+        if let Some(block_time) = self.block_time {
+            check_block_time(block_time).map_err(|msg| format!("Invalid genesis block_time: {}", msg))?;
+        }
+        self.initial_accounts().map(|_| ())
+    }
+
+    fn initial_accounts(&self) -> Result<HashMap<String, Account>, String> {
+        let mut accounts = HashMap::new();
+        for account in &self.accounts {
+            create_account(&mut accounts, &account.name, account.balance, &account.public_key)
+                .map_err(|msg| format!("Invalid genesis account: {}", msg))?;
+        }
+        Ok(accounts)
+    }
+
+    /// Block 0 of the chain, creating every genesis account
+    pub(crate) fn block(&self) -> Result<Block, String> {
+        self.check()?;
+        let transactions = self.accounts.iter()
+            .map(|account| Transaction::CreateAccount {
+                name: account.name.clone(),
+                balance: account.balance,
+                public_key: account.public_key.clone(),
+            })
+            .collect();
+        let accounts = self.initial_accounts()?;
+        Ok(Block::seal(&self.chain_id, 0, ZERO_HASH.to_string(), self.timestamp, transactions, &accounts))
+    }
+}
+
+fn serialize_block_time<S: Serializer>(block_time: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    block_time.map(|block_time| format!("{}ms", block_time.as_millis())).serialize(serializer)
+}
+
+/// Reads the block time as a plain number of seconds, or as a string like `--block-time` takes
+fn deserialize_block_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BlockTime {
+        Seconds(u64),
+        Text(String),
+    }
+    match Option::<BlockTime>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(BlockTime::Seconds(seconds)) => Ok(Some(Duration::from_secs(seconds))),
+        Some(BlockTime::Text(block_time)) => parse_block_time(&block_time).map(Some).map_err(serde::de::Error::custom),
+    }
+}
@@ -0,0 +1,35 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Parent hash of the very first block, since it has no parent to point to
+pub(crate) const ZERO_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Hashes the json representation of `value`
+/// NOTE: serde_json keeps struct fields in declaration order, so this is deterministic
+///       as long as we don't hash maps with a random iteration order (use BTreeMap instead).
+pub(crate) fn hash_of<T: Serialize + ?Sized>(value: &T) -> String {
+    sha256_hex(&serde_json::to_vec(value).expect("Our data structures should always serialize"))
+}
+
+/// Binary merkle tree root over already computed hashes.
+/// An odd node out is paired with itself, like Bitcoin does.
+pub(crate) fn merkle_root(hashes: &[String]) -> String {
+    if hashes.is_empty() {
+        return ZERO_HASH.to_string();
+    }
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        level = level.chunks(2)
+            .map(|pair| {
+                let left = &pair[0];
+                let right = pair.get(1).unwrap_or(left);
+                sha256_hex(format!("{}{}", left, right).as_bytes())
+            })
+            .collect();
+    }
+    level.pop().expect("There is always one node left at the root")
+}
@@ -0,0 +1,248 @@
+use std::fmt;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::node::{self, ConnectionLimit};
+use crate::protocol::{Answer, ErrorCode, ProtocolError, MAX_REQUEST_SIZE};
+use crate::Transaction;
+
+/// How long `stop` waits for tiny_http to let go of its listener, checking every 10ms
+const LISTENER_RELEASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The REST API being served, until it is stopped
+pub(crate) struct HttpServer {
+    server: Arc<Server>,
+    /// Hands the requests to their own threads, holding on to the server until it is joined
+    incoming_thread: JoinHandle<()>,
+    pub(crate) addr: SocketAddr,
+}
+
+impl fmt::Debug for HttpServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpServer").field("addr", &self.addr).finish_non_exhaustive()
+    }
+}
+
+impl HttpServer {
+    /// Stops accepting requests, and returns once the address is released, so that another server can take it.
+    /// The requests being served still get their response.
+    /// -- NOTE: Dropping the last `Server` only tells the tiny_http accept thread to stop, without joining it,
+    ///          so we wait until its listener stops taking connections.
+    pub(crate) fn stop(self) {
+        self.server.unblock();
+        if self.incoming_thread.join().is_err() {
+            log::error!("The HTTP server stopped abruptly");
+        }
+        drop(self.server);
+        let address = node::wake_up_address(self.addr);
+        for _ in 0..LISTENER_RELEASE_TIMEOUT.as_millis() / 10 {
+            if TcpStream::connect(address).is_err() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        log::warn!("The HTTP server still listens on {}", self.addr);
+    }
+}
+
+/// Starts the REST API on `addr`, every request being served on its own thread like the other transports,
+/// within the same limit of `connections`, the extra requests getting a 503.
+/// The server knows the address actually bound, with the port picked by the OS when asked for port 0.
+/// -- NOTE: The answers come from the mining thread, through the same channel as `process_remote_command`.
+pub(crate) fn serve(
+    addr: &str,
+    transactions_tx: mpsc::Sender<(mpsc::Sender<Answer>, Transaction)>,
+    connections: ConnectionLimit,
+) -> Result<HttpServer, String> {
+    let server = Server::http(addr)
+        .map_err(|e| format!("Could not start the HTTP server on {}: {}", addr, e))?;
+    let bound_addr = server.server_addr().to_ip()
+        .ok_or_else(|| format!("The HTTP server on {} should be bound to an IP address", addr))?;
+    let server = Arc::new(server);
+    let incoming = Arc::clone(&server);
+    let incoming_thread = thread::spawn(move || {
+        for request in incoming.incoming_requests() {
+            let Some(slot) = connections.acquire() else {
+                send(request, 503, error("Too many connections, try again later"));
+                continue;
+            };
+            let transactions_tx = transactions_tx.clone();
+            thread::spawn(move || {
+                respond(request, &transactions_tx);
+                drop(slot);
+            });
+        }
+    });
+    Ok(HttpServer { server, incoming_thread, addr: bound_addr })
+}
+
+fn respond(mut request: Request, transactions_tx: &mpsc::Sender<(mpsc::Sender<Answer>, Transaction)>) {
+    let mut body = Vec::new();
+    let read = request.as_reader().take(MAX_REQUEST_SIZE as u64 + 1).read_to_end(&mut body);
+    let (status, json) = match read {
+        Err(e) => (400, error(format!("Could not read the request body: {}", e))),
+        Ok(_) if body.len() > MAX_REQUEST_SIZE =>
+            (413, error(format!("Request bodies should be at most {} bytes long", MAX_REQUEST_SIZE))),
+        Ok(_) => route(request.method(), request.url(), &body,
+                       |transaction| crate::node::submit(transactions_tx, transaction)),
+    };
+    send(request, status, json);
+}
+
+fn send(request: Request, status: u16, json: Value) {
+    let content_type = Header::from_bytes("Content-Type", "application/json")
+        .expect("A static header should be valid");
+    let response = Response::from_string(json.to_string())
+        .with_status_code(status)
+        .with_header(content_type);
+    if let Err(e) = request.respond(response) {
+        log::warn!("Couldn't respond to an HTTP request: {}", e);
+    }
+}
+
+/// Maps the request to a status code and a json body, submitting the transactions (and queries) with `submit`
+fn route<F>(method: &Method, url: &str, body: &[u8], submit: F) -> (u16, Value)
+    where F: Fn(Transaction) -> Result<Answer, ProtocolError> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let segments = path.split('/').filter(|segment| !segment.is_empty()).map(percent_decode).collect::<Vec<_>>();
+    let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+    let result = match (method, segments.as_slice()) {
+        (Method::Get, ["accounts", name]) => account(name, &submit),
+        (Method::Get, ["blocks", block]) => submit(Transaction::GetBlock { block: block.to_string() })
+            .map(|answer| match answer {
+                Answer::Block(block) => (200, serde_json::to_value(block).expect("Our blocks should always serialize")),
+                answer => (404, error(answer.to_string())),
+            }),
+        (Method::Post, ["transactions"]) => match serde_json::from_slice::<Transaction>(body) {
+            Ok(transaction @ (Transaction::CreateAccount { .. } | Transaction::Transfer(_))) => submit(transaction)
+                .map(|answer| match answer {
+                    Answer::AccountCreationAccepted { id, .. } | Answer::TransferAccepted { id, .. } =>
+                        (202, json!({ "id": id })),
+                    Answer::Rejected { id, reason } => (422, json!({ "error": reason, "id": id })),
+                    answer => (500, error(format!("Unexpected answer from the node: {}", answer))),
+                }),
+            Ok(_) => Ok((400, error("Only CreateAccount and Transfer transactions can be posted"))),
+            Err(e) => Ok((400, error(format!("Invalid transaction: {}", e)))),
+        },
+        (Method::Get, ["mempool"]) => submit(Transaction::Mempool)
+            .map(|answer| match answer {
+                Answer::Mempool(transactions) =>
+                    (200, serde_json::to_value(transactions).expect("Our transactions should always serialize")),
+                answer => (500, error(format!("Unexpected answer from the node: {}", answer))),
+            }),
+        (_, ["accounts", _] | ["blocks", _] | ["transactions"] | ["mempool"]) =>
+            Ok((405, error(format!("{} is not allowed on {}", method, path)))),
+        _ => Ok((404, error(format!("No route for {}", path)))),
+    };
+    result.unwrap_or_else(|e| match e.code {
+        ErrorCode::NodeUnavailable => (503, error(e.message)),
+        _ => (500, error(e.message)),
+    })
+}
+
+/// The account balances, and the nonce to sign its next transfer with
+fn account<F>(name: &str, submit: &F) -> Result<(u16, Value), ProtocolError>
+    where F: Fn(Transaction) -> Result<Answer, ProtocolError> {
+    Ok(match submit(Transaction::Account { name: name.to_string() })? {
+        Answer::Account { name, balance, projected_balance, nonce } =>
+            (200, json!({ "name": name, "balance": balance, "projected_balance": projected_balance, "nonce": nonce })),
+        Answer::AccountNotFound { name } => (404, error(format!("No account found for {}", name))),
+        answer => (500, error(format!("Unexpected answer from the node: {}", answer))),
+    })
+}
+
+fn error(message: impl Into<String>) -> Value {
+    json!({ "error": message.into() })
+}
+
+/// Decodes the `%XX` escapes of a path segment, like the space in `/accounts/bob%20smith`
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers like a node knowing only "bob smith", with a balance of 1000 and nothing pending
+    fn submit(transaction: Transaction) -> Result<Answer, ProtocolError> {
+        Ok(match transaction {
+            Transaction::Account { name } if name == "bob smith" =>
+                Answer::Account { name, balance: 1000, projected_balance: 1000, nonce: 3 },
+            Transaction::Account { name } => Answer::AccountNotFound { name },
+            Transaction::GetBlock { block } => Answer::BlockNotFound { block },
+            Transaction::Mempool => Answer::Mempool(Vec::new()),
+            transaction @ Transaction::Transfer(_) =>
+                Answer::Rejected { id: transaction.id(), reason: "Invalid signature".to_string() },
+            transaction => Answer::AccountCreationAccepted { name: String::new(), balance: 0, id: transaction.id() },
+        })
+    }
+
+    #[test]
+    fn accounts_are_found_by_their_escaped_name() {
+        let (status, body) = route(&Method::Get, "/accounts/bob%20smith?fields=all", b"", submit);
+        let (missing_status, _) = route(&Method::Get, "/accounts/alice", b"", submit);
+
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({ "name": "bob smith", "balance": 1000, "projected_balance": 1000, "nonce": 3 }));
+        assert_eq!(missing_status, 404);
+    }
+
+    #[test]
+    fn posted_transactions_are_accepted_or_rejected() {
+        let (accepted, accepted_body) = route(&Method::Post, "/transactions",
+                                              br#"{"CreateAccount":{"name":"alice","balance":5,"public_key":null}}"#, submit);
+        let (rejected, rejected_body) = route(&Method::Post, "/transactions",
+                                              br#"{"Transfer":{"sender":"bob","receiver":"alice","balance":1,"nonce":0,"signature":"00"}}"#, submit);
+        let (query, _) = route(&Method::Post, "/transactions", br#""LatestBlock""#, submit);
+        let (invalid, _) = route(&Method::Post, "/transactions", b"{", submit);
+
+        assert_eq!(accepted, 202);
+        assert!(accepted_body["id"].is_string());
+        assert_eq!(rejected, 422);
+        assert_eq!(rejected_body["error"], "Invalid signature");
+        assert_eq!(query, 400);
+        assert_eq!(invalid, 400);
+    }
+
+    #[test]
+    fn unknown_routes_and_methods_are_refused() {
+        assert_eq!(route(&Method::Get, "/blocks/12", b"", submit).0, 404);
+        assert_eq!(route(&Method::Get, "/mempool", b"", submit), (200, json!([])));
+        assert_eq!(route(&Method::Delete, "/mempool", b"", submit).0, 405);
+        assert_eq!(route(&Method::Get, "/transactions", b"", submit).0, 405);
+        assert_eq!(route(&Method::Get, "/wallets", b"", submit).0, 404);
+    }
+
+    #[test]
+    fn an_unavailable_node_is_a_503() {
+        let unavailable = |_| Err(ProtocolError::new(ErrorCode::NodeUnavailable, "The node is gone"));
+
+        assert_eq!(route(&Method::Get, "/mempool", b"", unavailable).0, 503);
+    }
+}
@@ -0,0 +1,295 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::protocol::{Answer, ProtocolError, TransactionStatus};
+use crate::{Transaction, TransactionTransfer};
+
+/// Standard JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// Our own error code, for the transactions refused by the node
+const TRANSACTION_REJECTED: i64 = -32000;
+
+/// A JSON-RPC call, sent on the same connections as our own protocol.
+/// -- NOTE: Our own requests always start with `{"command"`, so the `jsonrpc` member (or a batch array) tells them apart.
+#[derive(Deserialize, Debug)]
+struct Call {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+    /// Missing for notifications, which get no response
+    #[serde(default, deserialize_with = "present")]
+    id: Option<Value>,
+}
+
+/// Tells an explicit `"id": null` (still answered) from a missing id (a notification)
+fn present<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), data: None }
+    }
+}
+
+#[derive(Deserialize)]
+struct NameParams {
+    name: String,
+    #[serde(default)]
+    pending: bool,
+}
+
+#[derive(Deserialize)]
+struct CreateAccountParams {
+    name: String,
+    balance: u64,
+    #[serde(default)]
+    public_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BlockParams {
+    /// Number or hash of the block
+    block: BlockRef,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlockRef {
+    Number(usize),
+    Hash(String),
+}
+
+#[derive(Deserialize)]
+struct TransactionParams {
+    id: String,
+}
+
+/// Whether the request line is meant for the JSON-RPC endpoint, rather than our own protocol
+pub(crate) fn is_json_rpc(line: &[u8]) -> bool {
+    match serde_json::from_slice::<Value>(line) {
+        Ok(Value::Array(_)) => true,
+        Ok(Value::Object(object)) => object.contains_key("jsonrpc"),
+        Ok(_) => false,
+        // NOTE: Invalid json can't be told apart, but a JSON-RPC client still deserves a parse error
+        Err(_) => line.windows(b"\"jsonrpc\"".len()).any(|window| window == b"\"jsonrpc\""),
+    }
+}
+
+/// Answers a single call or a batch, submitting the transactions with `submit`.
+/// Returns `None` when there is nothing to answer, because only notifications were sent.
+pub(crate) fn handle<F>(line: &[u8], submit: F) -> Option<String>
+    where F: Fn(Transaction) -> Result<Answer, ProtocolError> {
+    let response = match serde_json::from_slice::<Value>(line) {
+        Err(e) => Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, format!("Parse error: {}", e)))),
+        Ok(Value::Array(calls)) if calls.is_empty() =>
+            Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "Invalid Request: empty batch"))),
+        Ok(Value::Array(calls)) => {
+            let responses = calls.into_iter()
+                .filter_map(|call| handle_call(call, &submit))
+                .collect::<Vec<_>>();
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        Ok(call) => handle_call(call, &submit),
+    };
+    response.map(|response| response.to_string())
+}
+
+fn handle_call<F>(call: Value, submit: &F) -> Option<Value>
+    where F: Fn(Transaction) -> Result<Answer, ProtocolError> {
+    let call = match serde_json::from_value::<Call>(call) {
+        Ok(call) if call.jsonrpc == "2.0" => call,
+        Ok(call) => return Some(error_response(call.id.unwrap_or(Value::Null),
+                                               RpcError::new(INVALID_REQUEST, "Invalid Request: jsonrpc should be \"2.0\""))),
+        Err(e) => return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, format!("Invalid Request: {}", e)))),
+    };
+    let result = call_method(&call.method, call.params.unwrap_or(Value::Null), submit);
+    let id = call.id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => error_response(id, error),
+    })
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+}
+
+fn call_method<F>(method: &str, params: Value, submit: &F) -> Result<Value, RpcError>
+    where F: Fn(Transaction) -> Result<Answer, ProtocolError> {
+    let transaction = match method {
+        "getBalance" => {
+            let NameParams { name, pending } = parse_params(params)?;
+            Transaction::Balance { name, pending }
+        }
+        "createAccount" => {
+            let CreateAccountParams { name, balance, public_key } = parse_params(params)?;
+            Transaction::CreateAccount { name, balance, public_key }
+        }
+        "sendTransfer" => Transaction::Transfer(parse_params::<TransactionTransfer>(params)?),
+        "getBlock" => {
+            let BlockParams { block } = parse_params(params)?;
+            Transaction::GetBlock {
+                block: match block {
+                    BlockRef::Number(block_num) => block_num.to_string(),
+                    BlockRef::Hash(hash) => hash,
+                }
+            }
+        }
+        "getTransaction" => {
+            let TransactionParams { id } = parse_params(params)?;
+            Transaction::TxStatus { id }
+        }
+        _ => return Err(RpcError::new(METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+    };
+    let answer = submit(transaction).map_err(|error| RpcError {
+        code: INTERNAL_ERROR,
+        message: error.message,
+        data: Some(json!({ "code": error.code })),
+    })?;
+    Ok(match answer {
+        Answer::AccountCreationAccepted { id, .. } | Answer::TransferAccepted { id, .. } => json!({ "id": id }),
+        Answer::Rejected { id, reason } => return Err(RpcError {
+            code: TRANSACTION_REJECTED,
+            message: reason,
+            data: Some(json!({ "id": id })),
+        }),
+        Answer::Balance { name, balance, projected_balance } =>
+            json!({ "name": name, "balance": balance, "projected_balance": projected_balance }),
+        // NOTE: Like most JSON-RPC nodes, what doesn't exist is a null result rather than an error
+        Answer::AccountNotFound { .. } | Answer::BlockNotFound { .. }
+        | Answer::TransactionStatus { status: TransactionStatus::Unknown, .. } => Value::Null,
+        Answer::TransactionStatus { id, status, transaction } => {
+            let block_num = match status {
+                TransactionStatus::Included { block_num } => Some(block_num),
+                _ => None,
+            };
+            json!({ "id": id, "status": status, "block_num": block_num, "transaction": transaction })
+        }
+        Answer::Block(block) => serde_json::to_value(block).expect("Our blocks should always serialize"),
+        answer => return Err(RpcError::new(INTERNAL_ERROR, format!("Unexpected answer from the node: {}", answer))),
+    })
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid params: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers like a node knowing only bob, with a balance of 1000
+    fn submit(transaction: Transaction) -> Result<Answer, ProtocolError> {
+        Ok(match transaction {
+            Transaction::Balance { name, .. } if name == "bob" =>
+                Answer::Balance { name, balance: 1000, projected_balance: None },
+            Transaction::Balance { name, .. } => Answer::AccountNotFound { name },
+            Transaction::Transfer(transfer) => Answer::Rejected {
+                id: Transaction::Transfer(transfer).id(),
+                reason: "Invalid signature".to_string(),
+            },
+            Transaction::TxStatus { id } => Answer::TransactionStatus {
+                id,
+                status: TransactionStatus::Included { block_num: 3 },
+                transaction: Some(Transaction::CreateAccount { name: "bob".to_string(), balance: 1000, public_key: None }),
+            },
+            transaction => Answer::AccountCreationAccepted { name: String::new(), balance: 0, id: transaction.id() },
+        })
+    }
+
+    fn call(request: &str) -> Value {
+        let response = handle(request.as_bytes(), submit).expect("A response was expected");
+        serde_json::from_str(&response).expect("The response should be json")
+    }
+
+    #[test]
+    fn requests_are_told_apart_from_our_own_protocol() {
+        assert!(is_json_rpc(br#"{"jsonrpc":"2.0","method":"getBalance","params":["bob"],"id":1}"#));
+        assert!(is_json_rpc(br#"[]"#));
+        assert!(is_json_rpc(br#"{"jsonrpc": not json"#));
+        assert!(!is_json_rpc(br#"{"command":{"Balance":{"name":"bob","pending":false}}}"#));
+        assert!(!is_json_rpc(b"not json"));
+    }
+
+    #[test]
+    fn params_can_be_named_or_positional() {
+        let named = call(r#"{"jsonrpc":"2.0","method":"getBalance","params":{"name":"bob"},"id":1}"#);
+        let positional = call(r#"{"jsonrpc":"2.0","method":"getBalance","params":["bob", true],"id":"two"}"#);
+
+        assert_eq!(named, json!({ "jsonrpc": "2.0", "result": { "name": "bob", "balance": 1000, "projected_balance": null }, "id": 1 }));
+        assert_eq!(positional["result"]["balance"], 1000);
+        assert_eq!(positional["id"], "two");
+    }
+
+    #[test]
+    fn unknown_accounts_are_null() {
+        let response = call(r#"{"jsonrpc":"2.0","method":"getBalance","params":["alice"],"id":1}"#);
+
+        assert_eq!(response["result"], Value::Null);
+        assert!(response.get("error").is_none());
+    }
+
+    #[test]
+    fn transactions_come_with_their_status_and_block() {
+        let response = call(r#"{"jsonrpc":"2.0","method":"getTransaction","params":["abc"],"id":1}"#);
+
+        assert_eq!(response["result"]["id"], "abc");
+        assert_eq!(response["result"]["block_num"], 3);
+        assert_eq!(response["result"]["status"], json!({ "Included": { "block_num": 3 } }));
+        assert_eq!(response["result"]["transaction"]["CreateAccount"]["name"], "bob");
+    }
+
+    #[test]
+    fn errors_use_the_standard_codes() {
+        let parse_error = call(r#"{"jsonrpc":"2.0","method":"#);
+        let invalid_request = call(r#"{"jsonrpc":"2.0","id":1}"#);
+        let wrong_version = call(r#"{"jsonrpc":"1.0","method":"getBalance","id":1}"#);
+        let unknown_method = call(r#"{"jsonrpc":"2.0","method":"mine","id":1}"#);
+        let invalid_params = call(r#"{"jsonrpc":"2.0","method":"getBalance","params":{"nom":"bob"},"id":1}"#);
+        let rejected = call(r#"{"jsonrpc":"2.0","method":"sendTransfer","params":
+            {"sender":"bob","receiver":"alice","balance":1,"nonce":0,"signature":"00"},"id":1}"#);
+
+        assert_eq!(parse_error["error"]["code"], PARSE_ERROR);
+        assert_eq!(parse_error["id"], Value::Null);
+        assert_eq!(invalid_request["error"]["code"], INVALID_REQUEST);
+        assert_eq!(wrong_version["error"]["code"], INVALID_REQUEST);
+        assert_eq!(wrong_version["id"], 1);
+        assert_eq!(unknown_method["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(invalid_params["error"]["code"], INVALID_PARAMS);
+        assert_eq!(rejected["error"]["code"], TRANSACTION_REJECTED);
+        assert!(rejected["error"]["data"]["id"].is_string());
+    }
+
+    #[test]
+    fn batches_answer_every_call_except_notifications() {
+        let response = call(r#"[
+            {"jsonrpc":"2.0","method":"getBalance","params":["bob"],"id":1},
+            {"jsonrpc":"2.0","method":"getBalance","params":["bob"]},
+            {"jsonrpc":"2.0","method":"mine","id":2},
+            42
+        ]"#);
+        let responses = response.as_array().expect("A batch should get an array");
+
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0]["result"]["balance"], 1000);
+        assert_eq!(responses[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(responses[2]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(call("[]")["error"]["code"], INVALID_REQUEST);
+        assert!(handle(br#"[{"jsonrpc":"2.0","method":"getBalance","params":["bob"]}]"#, submit).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+
+use crate::hashing::hash_of;
+use crate::TransactionTransfer;
+
+/// Creates a new keypair, saves its secret key (hex encoded) in `path`, and returns its public key
+pub fn keygen(path: &Path) -> Result<String, String> {
+    let signing_key = SigningKey::generate(&mut OsRng);
+    let mut options = OpenOptions::new();
+    // NOTE: We never overwrite a key, as it could be the only way to spend from an account.
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)
+        .map_err(|e| format!("Could not create the key file {}: {}", path.display(), e))?;
+    file.write_all((hex::encode(signing_key.to_bytes()) + "\n").as_bytes())
+        .map_err(|e| format!("Could not write the key file {}: {}", path.display(), e))?;
+    Ok(hex::encode(signing_key.verifying_key().to_bytes()))
+}
+
+pub fn load_signing_key(path: &Path) -> Result<SigningKey, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read the key file {}: {}", path.display(), e))?;
+    let bytes = hex::decode(content.trim()).ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| format!("The key file {} should contain a hex encoded secret key", path.display()))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+pub(crate) fn parse_public_key(public_key: &str) -> Result<VerifyingKey, String> {
+    hex::decode(public_key).ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| format!("Invalid public key: {}", public_key))
+}
+
+/// What the sender signs: every field of the transfer, except the signature itself,
+/// and the id of the chain it is meant for, so that it can't be replayed on another chain
+fn signed_payload(chain_id: &str, transfer: &TransactionTransfer) -> String {
+    hash_of(&(chain_id, &transfer.sender, &transfer.receiver, transfer.balance, transfer.nonce))
+}
+
+pub fn sign_transfer(signing_key: &SigningKey, chain_id: &str, transfer: &TransactionTransfer) -> String {
+    hex::encode(signing_key.sign(signed_payload(chain_id, transfer).as_bytes()).to_bytes())
+}
+
+pub(crate) fn verify_transfer(public_key: &str, chain_id: &str, transfer: &TransactionTransfer) -> Result<(), String> {
+    let invalid_signature = || format!("Invalid signature from {}: cannot send {} to {}",
+                                       transfer.sender, transfer.balance, transfer.receiver);
+    let signature = hex::decode(&transfer.signature).ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(invalid_signature)?;
+    parse_public_key(public_key)?
+        .verify(signed_payload(chain_id, transfer).as_bytes(), &signature)
+        .map_err(|_| invalid_signature())
+}
@@ -0,0 +1,342 @@
+//! A toy blockchain: a single node mining a block every `block_time`, and the client talking to it.
+//! -- NOTE: The `toy-blockchain-cli` binary is a thin layer over this library, services and tests can
+//!          embed a `node::Node` in-process, or drive a `block_chain::BlockChain` directly.
+//!          What the node does is only reported through the `log` facade, never printed by the library.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::Subcommand;
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+
+use protocol::Answer;
+use storage::ChainStore;
+
+pub mod block_chain;
+pub mod client;
+pub mod clock;
+pub mod genesis;
+mod hashing;
+mod http_api;
+mod json_rpc;
+pub mod keys;
+pub mod mempool;
+pub mod node;
+pub mod protocol;
+pub mod storage;
+
+pub const LOCAL_BLOCKCHAIN_LISTEN_ADDR: &str = "0.0.0.0:9966";
+pub const LOCAL_BLOCKCHAIN_ADDR: &str = "127.0.0.1:9966";
+
+/// The commands of the CLI, the client ones being sent to the node as they are
+#[derive(Subcommand, Serialize, Deserialize, Debug, Clone)]
+pub enum Commands {
+    #[command(name = "start_node")]
+    /// Starts a new local blockchain, that mines a block every `block_time` (default 10s).
+    /// -- NOTE: To run several nodes on the same machine, give each one its own `--listen` address,
+    ///          otherwise you'll see a "Address already in use" error
+    StartNode {
+        #[clap(long, default_value = LOCAL_BLOCKCHAIN_LISTEN_ADDR)]
+        /// Address the node accepts the client commands on
+        listen: String,
+        #[clap(long)]
+        /// Path of a local socket the node also accepts the client commands on.
+        /// Its access is controlled by the filesystem permissions, no TCP port is needed to reach it.
+        listen_socket: Option<PathBuf>,
+        #[clap(long)]
+        /// Address of an optional HTTP server, exposing the node as a REST API
+        http: Option<String>,
+        #[clap(long, value_parser = parse_block_time)]
+        /// Time between each block, like `500ms`, `2s` or `1m` (a plain number is in seconds),
+        /// overriding the genesis one (default 10s, at most a day)
+        block_time: Option<Duration>,
+        #[clap(long)]
+        /// Directory where the sealed blocks are persisted, and restored from on startup
+        data_dir: Option<PathBuf>,
+        #[clap(long)]
+        /// Json file defining the chain id, block time and initial accounts, sealed as block 0
+        genesis: Option<PathBuf>,
+        #[clap(long, default_value_t = mempool::DEFAULT_MEMPOOL_SIZE,
+               value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        /// How many transfers can wait for the next block, the oldest ones being evicted first
+        mempool_size: usize,
+    },
+    #[command(name = "create_account")]
+    /// Creates a new account with an initial balance, once the next block is mined
+    /// Is a no-op if the account already exists, you'll just get an error message
+    CreateAccount {
+        /// Name of the account holder
+        name: String,
+        /// starting balance on the account
+        balance: u64,
+        #[clap(long)]
+        /// Hex encoded public key (from `keygen`) that will have to sign the transfers from this account.
+        /// Without one, the account can only receive tokens.
+        public_key: Option<String>,
+    },
+    #[command(name = "balance")]
+    /// Returns the balance of the account, if it exists
+    Balance {
+        /// Name of the account holder
+        name: String,
+        #[clap(long)]
+        /// Also show the balance projected after the pending transfers are mined
+        pending: bool,
+    },
+    #[command(name = "nonce")]
+    /// Returns the nonce expected for the next transfer from the account, if it exists,
+    /// counting the transfers it already has pending
+    Nonce {
+        /// Name of the account holder
+        name: String,
+    },
+    #[command(name = "transfer")]
+    /// Ask for a token transfer stored in the next mined block
+    /// It will check twice if the transaction is valid, since balance can change
+    Transfer {
+        /// Name of the sending account holder
+        sender: String,
+        /// Name of the receiving account holder
+        receiver: String,
+        /// starting balance on the account
+        balance: u64,
+        #[clap(long)]
+        /// Must be the sender's next nonce (see the `nonce` command), so the transfer can't be replayed
+        nonce: u64,
+        #[clap(long)]
+        #[serde(skip)]
+        /// File containing the sender's secret key, used to sign the transfer before sending it
+        /// NOTE: It never leaves this machine, only the signature is sent to the node.
+        key: PathBuf,
+        #[clap(skip)]
+        /// Hex encoded signature of the transfer, filled from `key`
+        signature: String,
+    },
+    #[command(name = "tx_status")]
+    /// Tells whether the transaction is pending, included in a block, or was rejected and why
+    TxStatus {
+        /// Id of the transaction, as returned when it was submitted
+        id: String,
+    },
+    #[command(name = "get_block")]
+    /// Returns the block, with its transactions, as json
+    GetBlock {
+        /// Number or hash of the block
+        block: String,
+    },
+    #[command(name = "latest_block")]
+    /// Returns the last mined block, with its transactions, as json
+    LatestBlock,
+    #[command(name = "list_blocks")]
+    /// Returns a json array of consecutive blocks, with their transactions
+    /// -- NOTE: At most 100 blocks are returned at once
+    ListBlocks {
+        #[clap(long)]
+        /// First block number to list (default 0)
+        from: Option<usize>,
+        #[clap(long)]
+        /// Last block number to list, included (default to the latest block)
+        to: Option<usize>,
+    },
+    #[command(name = "history")]
+    /// Returns the mined transactions that touched the account, ordered by block number, as json
+    History {
+        /// Name of the account holder
+        name: String,
+        #[clap(long, default_value_t = 1)]
+        /// Page number, starting at 1
+        page: usize,
+        #[clap(long, default_value_t = 20)]
+        /// Number of transactions per page (at most 100)
+        per_page: usize,
+    },
+    #[command(name = "mempool")]
+    /// Returns the transactions waiting for the next block, oldest first, as json
+    Mempool,
+    #[command(name = "watch")]
+    /// Prints the blocks as they get sealed, and the accounts they change, until interrupted
+    Watch {
+        #[clap(long = "account")]
+        /// Only report the changes of this account, can be repeated (default to every account)
+        accounts: Vec<String>,
+    },
+    #[command(name = "verify_chain")]
+    /// Replays every block from the first one, re-checking all of their transactions,
+    /// and reports the first invalid block, if any
+    VerifyChain {
+        #[clap(long)]
+        /// Verify the blocks stored in this directory instead of asking the running node.
+        /// It is only read, and must already contain a blocks file
+        data_dir: Option<PathBuf>,
+    },
+    #[command(name = "keygen")]
+    /// Creates a new keypair, saves its secret key in a file, and prints its public key,
+    /// to be given to `create_account --public-key`
+    Keygen {
+        /// File where the secret key is saved, it is never overwritten
+        path: PathBuf,
+    },
+}
+
+/// Parses `--block-time` values (and the genesis ones), so that an invalid one is reported like any other invalid argument
+pub(crate) fn parse_block_time(block_time: &str) -> Result<Duration, String> {
+    let (amount, to_duration): (&str, fn(u64) -> Duration) = if let Some(millis) = block_time.strip_suffix("ms") {
+        (millis, Duration::from_millis)
+    } else if let Some(seconds) = block_time.strip_suffix('s') {
+        (seconds, Duration::from_secs)
+    } else if let Some(minutes) = block_time.strip_suffix('m') {
+        (minutes, |minutes| Duration::from_secs(minutes.saturating_mul(60)))
+    } else {
+        (block_time, Duration::from_secs)
+    };
+    let block_time = amount.parse::<u64>().map(to_duration)
+        .map_err(|_| "expected a duration like `500ms`, `2s` or `1m`".to_string())?;
+    block_chain::check_block_time(block_time)?;
+    Ok(block_time)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransactionTransfer {
+    /// Name of the sending account holder
+    pub sender: String,
+    /// Name of the receiving account holder
+    pub receiver: String,
+    /// starting balance on the account
+    pub balance: u64,
+    /// Position of this transfer among the ones sent by the sender, starting at 0
+    pub nonce: u64,
+    /// Hex encoded signature of the other fields and of the chain id, by the sender's key
+    pub signature: String,
+}
+
+impl TransactionTransfer {
+    /// A transfer signed by the sender's key, as the nodes of the `chain_id` chain expect it
+    pub fn signed(signing_key: &SigningKey, chain_id: &str, sender: &str, receiver: &str, balance: u64, nonce: u64) -> Self {
+        let mut transfer = Self {
+            sender: sender.to_string(),
+            receiver: receiver.to_string(),
+            balance,
+            nonce,
+            signature: String::new(),
+        };
+        transfer.signature = keys::sign_transfer(signing_key, chain_id, &transfer);
+        transfer
+    }
+}
+
+impl From<TransactionTransfer> for Commands {
+    /// The `transfer` command carrying an already signed transfer, as sent to the node
+    fn from(transfer: TransactionTransfer) -> Self {
+        Commands::Transfer {
+            sender: transfer.sender,
+            receiver: transfer.receiver,
+            balance: transfer.balance,
+            nonce: transfer.nonce,
+            key: PathBuf::new(),
+            signature: transfer.signature,
+        }
+    }
+}
+
+/// What the mining thread processes: the transactions to include in a block, and the queries answered right away
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Transaction {
+    CreateAccount {
+        /// Name of the account holder
+        name: String,
+        /// starting balance on the account
+        balance: u64,
+        /// Hex encoded public key allowed to send from this account
+        public_key: Option<String>,
+    },
+    Transfer(TransactionTransfer),
+    Balance {
+        /// Name of the account holder
+        name: String,
+        /// Whether to also project the pending transfers on the balance
+        pending: bool,
+    },
+    Nonce {
+        /// Name of the account holder
+        name: String,
+    },
+    /// Balance, projected balance and nonce of an account, read together
+    Account {
+        /// Name of the account holder
+        name: String,
+    },
+    TxStatus {
+        /// Id of the transaction, as returned when it was submitted
+        id: String,
+    },
+    GetBlock {
+        /// Number or hash of the block
+        block: String,
+    },
+    LatestBlock,
+    ListBlocks {
+        /// First block number to list
+        from: Option<usize>,
+        /// Last block number to list, included
+        to: Option<usize>,
+    },
+    History {
+        /// Name of the account holder
+        name: String,
+        /// Page number, starting at 1
+        page: usize,
+        /// Number of transactions per page
+        per_page: usize,
+    },
+    Mempool,
+    /// Keeps the client informed of every new block, and of the changes of the given accounts (all if empty)
+    Subscribe {
+        accounts: Vec<String>,
+    },
+    VerifyChain,
+}
+
+impl Transaction {
+    /// Deterministic id of the transaction: the hash of its content
+    pub fn id(&self) -> String {
+        hashing::hash_of(self)
+    }
+}
+
+
+/// Replays the blocks stored in `data_dir`, without any node running nor writing anything.
+/// -- NOTE: A missing data dir or blocks file is reported as invalid, it is most likely a typo in the path.
+pub fn verify_stored_chain(data_dir: &Path) -> Answer {
+    let blocks = match ChainStore::open_read_only(data_dir).and_then(|store| store.load()) {
+        Ok(blocks) => blocks,
+        Err(reason) => return Answer::ChainInvalid { reason },
+    };
+    match block_chain::validate_blocks(&blocks) {
+        Ok(_accounts) => Answer::ChainValid { blocks: blocks.len() },
+        Err(reason) => Answer::ChainInvalid { reason },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_times_are_parsed_with_their_unit() {
+        assert_eq!(parse_block_time("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_block_time("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_block_time("3"), Ok(Duration::from_secs(3)));
+        assert_eq!(parse_block_time("1m"), Ok(Duration::from_secs(60)));
+        assert!(parse_block_time("0s").is_err());
+        assert!(parse_block_time("2h").is_err());
+    }
+
+    #[test]
+    fn block_times_out_of_range_are_refused() {
+        assert_eq!(parse_block_time("1440m"), Ok(block_chain::MAX_BLOCK_TIME));
+        assert!(parse_block_time("1441m").is_err());
+        assert!(parse_block_time("18446744073709551615s").is_err());
+        assert!(parse_block_time("18446744073709551615m").is_err());
+    }
+}
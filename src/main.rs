@@ -1,203 +1,142 @@
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
 use std::string::String;
-use std::sync::mpsc;
-use std::thread;
 
-use clap::{Parser, Subcommand};
-use serde::{Deserialize, Serialize};
+use clap::Parser;
 
-use block_chain::BlockChain;
-
-mod block_chain;
+use toy_blockchain_cli::client::{NodeAddress, NodeClient};
+use toy_blockchain_cli::keys;
+use toy_blockchain_cli::node::Node;
+use toy_blockchain_cli::protocol::{Answer, ErrorCode, ProtocolError, Response};
+use toy_blockchain_cli::{verify_stored_chain, Commands, LOCAL_BLOCKCHAIN_ADDR};
 
 #[cfg(test)]
 mod acceptance_tests;
 
-const LOCAL_BLOCKCHAIN_LISTEN_ADDR: &str = "0.0.0.0:9966";
-const LOCAL_BLOCKCHAIN_ADDR: &str = "127.0.0.1:9966";
+/// Environment variable giving the node address to the client commands, when `--node` isn't used
+const NODE_ADDR_ENV_VAR: &str = "TOY_BLOCKCHAIN_NODE";
+/// Environment variable giving the node local socket to the client commands, when `--socket` isn't used
+const NODE_SOCKET_ENV_VAR: &str = "TOY_BLOCKCHAIN_SOCKET";
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None, arg_required_else_help = true)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    #[clap(long, global = true, env = NODE_ADDR_ENV_VAR, default_value = LOCAL_BLOCKCHAIN_ADDR)]
+    /// Address of the node that the client commands are sent to
+    node: String,
+    #[clap(long, global = true, env = NODE_SOCKET_ENV_VAR)]
+    /// Local socket of the node that the client commands are sent to, instead of its `--node` address
+    socket: Option<PathBuf>,
+    #[clap(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    /// How the client commands print the node answers
+    output: OutputFormat,
 }
 
-#[derive(Subcommand, Serialize, Deserialize, Debug)]
-enum Commands {
-    #[command(name = "start_node")]
-    /// Starts a new local blockchain, that mines a block every `block_time` (default 10s).
-    /// -- NOTE: For now, we use a single hardcoded address for the start_node.
-    ///       - So you'll need to un only a single one, or you'll see a "Address already in use" error
-    StartNode {
-        #[clap(long, default_value = "10")]
-        /// Seconds between each block
-        block_time: String,
-    },
-    #[command(name = "create_account")]
-    /// Creates a new account with an initial balance
-    /// Is a no-op if the account already exists, you'll just get an error message
-    CreateAccount {
-        /// Name of the account holder
-        name: String,
-        /// starting balance on the account
-        balance: u64,
-    },
-    #[command(name = "balance")]
-    /// Returns the balance of the account, if it exists
-    Balance {
-        /// Name of the account holder
-        name: String,
-    },
-    #[command(name = "transfer")]
-    /// Ask for a token transfer stored in the next mined block
-    /// It will check twice if the transaction is valid, since balance can change
-    Transfer {
-        /// Name of the sending account holder
-        sender: String,
-        /// Name of the receiving account holder
-        receiver: String,
-        /// starting balance on the account
-        balance: u64,
-    },
-}
+/// Prints what the node reports on stdout, for `start_node`
+struct StdoutLogger;
 
-#[derive(Debug, Clone)]
-struct TransactionTransfer {
-    /// Name of the sending account holder
-    pub sender: String,
-    /// Name of the receiving account holder
-    pub receiver: String,
-    /// starting balance on the account
-    pub balance: u64,
-}
+impl log::Log for StdoutLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", record.args());
+        }
+    }
 
-#[derive(Debug, Clone)]
-enum Transaction {
-    CreateAccount {
-        /// Name of the account holder
-        name: String,
-        /// starting balance on the account
-        balance: u64,
-    },
-    Transfer(TransactionTransfer),
-    Balance {
-        /// Name of the account holder
-        name: String,
-    },
+    fn flush(&self) {}
 }
 
+static LOGGER: StdoutLogger = StdoutLogger;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum OutputFormat {
+    /// Human readable sentences
+    Text,
+    /// One json line per answer, `{"Ok": ...}` or `{"Error": ...}`, for scripts
+    Json,
+}
 fn main() {
     let cli = Cli::parse();
+    let client = NodeClient::new(match &cli.socket {
+        Some(path) => NodeAddress::LocalSocket(path.clone()),
+        None => NodeAddress::Tcp(cli.node.clone()),
+    });
 
     match &cli.command {
-        Some(Commands::StartNode { block_time }) => {
-            start_node(block_time, LOCAL_BLOCKCHAIN_LISTEN_ADDR);
+        Some(Commands::StartNode { listen, listen_socket, http, block_time, data_dir, genesis, mempool_size }) => {
+            let mut node = Node::builder().listen(listen).mempool_size(*mempool_size);
+            if let Some(listen_socket) = listen_socket {
+                node = node.listen_socket(listen_socket);
+            }
+            if let Some(http) = http {
+                node = node.http(http);
+            }
+            if let Some(block_time) = block_time {
+                node = node.block_time(*block_time);
+            }
+            if let Some(data_dir) = data_dir {
+                node = node.data_dir(data_dir);
+            }
+            if let Some(genesis) = genesis {
+                node = node.genesis(genesis);
+            }
+            if log::set_logger(&LOGGER).is_ok() {
+                log::set_max_level(log::LevelFilter::Info);
+            }
+            node.start().expect("The node should start").wait();
         }
-        Some(command) => {
-            println!("{}", ask_node(command, LOCAL_BLOCKCHAIN_ADDR));
+        Some(command @ Commands::VerifyChain { data_dir: Some(data_dir) }) => {
+            print_result(command, Ok(verify_stored_chain(data_dir)), cli.output);
         }
-        _ => { unreachable!() }
-    }
-}
-
-fn start_node(block_time: &str, addr: &str) {
-    let block_time: u64 = block_time.parse().expect("Block time should be a number of seconds");
-    assert!(block_time > 0, "Block time should be a positive number of seconds");
-    // NOTE: We could have reused Commands::Transfer, but that could be bad "de-duplication"
-    // as these data structures don't serve the same purpose and could diverge in later development.
-    let (transactions_tx, transactions_rx) = mpsc::channel();
-
-    thread::spawn(move || {
-        let mut transactions_rx = transactions_rx;
-
-        let mut block_chain = BlockChain::new(block_time);
-        let mut transfers = Vec::new();
-        loop {
-            block_chain.try_mining(&mut transactions_rx, &mut transfers);
+        Some(Commands::Keygen { path }) => {
+            match (keys::keygen(path), cli.output) {
+                (Ok(public_key), OutputFormat::Text) =>
+                    println!("Saved a new secret key in {}\nPublic key: {}", path.display(), public_key),
+                (Ok(public_key), OutputFormat::Json) =>
+                    println!("{}", serde_json::json!({ "path": path, "public_key": public_key })),
+                (Err(msg), output) => print_failure(msg, output),
+            }
         }
-    });
-
-    let listener = TcpListener::bind(addr).unwrap();
-    loop {
-        if let Ok((stream, _addr)) = listener.accept() {
-            let mut stream = stream;
-            let mut buf = String::new();
-            let ret = BufReader::new(&stream).read_line(&mut buf);
-            if let Ok(val) = ret {
-                if val > 1 {
-                    let response = process_remote_command(
-                        transactions_tx.clone(),
-                        serde_json::from_str(&buf).expect("We should have received a serialized Commands"),
-                    ) + "\n";
-                    match stream.write_all(response.as_bytes()) {
-                        Err(v) => {
-                            println!("Couldn't respond: {} because {}", response, v);
-                        }
-                        a => {
-                            println!("Tried to respond: {} , and sent {:?} bytes", response, a);
-                        }
-                    }
-                }
+        Some(command @ Commands::Transfer { sender, receiver, balance, nonce, key, .. }) => {
+            match keys::load_signing_key(key) {
+                Ok(signing_key) => print_result(command, client.transfer(&signing_key, sender, receiver, *balance, *nonce),
+                                                cli.output),
+                Err(msg) => print_failure(msg, cli.output),
             }
         }
-    }
-}
-
-fn ask_node(command: &Commands, addr: &str) -> String {
-    if let Ok(mut stream) = TcpStream::connect(addr) {
-        // if stream.set_read_timeout(Some(Duration::from_secs(2))).is_err(){eprintln!("Could set read timeout")};
-        // if stream.set_write_timeout(Some(Duration::from_secs(2))).is_err() { eprintln!("Could set write timeout") };
-        // serde::json : Not as small over-the-wire as binary representation, but easier to debug
-        if let Ok(val) = stream.write_all((serde_json::to_string(command)
-            .expect("The command should be well formed already") + "\n").as_bytes()) {
-            let mut buf = String::new();
-            if let Ok(_val) = BufReader::new(stream).read_line(&mut buf) {
-                format!("{:?}: {}", command, String::from_utf8(buf.into()).expect("We should have sent utf8"))
-            } else {
-                "Could not read from server sending the command".to_string()
+        Some(command @ Commands::Watch { .. }) => {
+            let watched = client.watch(command, |answer| print_result(command, Ok(answer), cli.output));
+            if let Err(error) = watched {
+                print_result(command, Err(error), cli.output);
             }
-        } else {
-            "Could not write to server after initial connection".to_string()
         }
-    } else {
-        "Could not connect to server".to_string()
+        Some(command) => {
+            print_result(command, client.request(command), cli.output);
+        }
+        _ => { unreachable!() }
     }
 }
 
-
-fn process_remote_command(transactions_tx: mpsc::Sender<(mpsc::Sender<String>, Transaction)>, command: Commands) -> String {
-    let (msg_tx, msg_rx) = mpsc::channel();
-    match command {
-        Commands::StartNode { block_time: _ } => {
-            println!("We shouldn't receive that remotely");
-            unimplemented!("We don't allow restarting the node remotely.");
-        }
-        Commands::CreateAccount { name, balance } => {
-            transactions_tx.send((msg_tx,
-                                  Transaction::CreateAccount {
-                                      name,
-                                      balance,
-                                  })).expect("It should stay open until we kill the whole executable");
-            msg_rx.recv().expect("Should be an error message, in the worst case")
-        }
-        Commands::Balance { name } => {
-            transactions_tx.send((msg_tx,
-                                  Transaction::Balance {
-                                      name,
-                                  })).expect("It should stay open until we kill the whole executable");
-            msg_rx.recv().expect("Should be an error message, in the worst case")
-        }
-        Commands::Transfer { sender, receiver, balance } => {
-            transactions_tx.send((msg_tx,
-                                  Transaction::Transfer(TransactionTransfer {
-                                      sender,
-                                      receiver,
-                                      balance,
-                                  }))).expect("It should stay open until we kill the whole executable");
-            msg_rx.recv().expect("Should be an error message, in the worst case")
-        }
+fn print_result(command: &Commands, result: Result<Answer, ProtocolError>, output: OutputFormat) {
+    match (result, output) {
+        (Ok(answer), OutputFormat::Text) if matches!(command, Commands::Watch { .. }) => println!("{}", answer),
+        (Ok(answer), OutputFormat::Text) => println!("{:?}: {}", command, answer),
+        (Err(error), OutputFormat::Text) if error.code == ErrorCode::NodeUnreachable => println!("{}", error.message),
+        (Err(error), OutputFormat::Text) => println!("{:?}: Error {:?}: {}", command, error.code, error.message),
+        (result, OutputFormat::Json) => println!("{}", serde_json::to_string(&Response::from(result))
+            .expect("Our responses should always serialize")),
     }
 }
 
+/// Reports what went wrong before anything could be sent to the node
+fn print_failure(msg: String, output: OutputFormat) {
+    match output {
+        OutputFormat::Text => println!("{}", msg),
+        OutputFormat::Json => println!("{}", serde_json::to_string(&Response::Error(ProtocolError::new(ErrorCode::ClientFailure, msg)))
+            .expect("Our responses should always serialize")),
+    }
+}
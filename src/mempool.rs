@@ -0,0 +1,231 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Transaction, TransactionTransfer};
+
+/// How many transactions can wait for the next block, unless `start_node --mempool-size` says otherwise
+pub const DEFAULT_MEMPOOL_SIZE: usize = 1000;
+
+/// Transactions accepted by the node, waiting to be included in a block.
+/// They are identified by their hash, so the same transaction can only be pending once.
+#[derive(Debug)]
+pub(crate) struct Mempool {
+    max_size: usize,
+    /// Ids of the pending transactions, oldest first
+    order: VecDeque<String>,
+    transactions: HashMap<String, Transaction>,
+}
+
+/// A transaction waiting for the next block, as listed by `mempool`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingTransaction {
+    pub id: String,
+    pub transaction: Transaction,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMPOOL_SIZE).expect("The default mempool size is positive")
+    }
+}
+
+impl Mempool {
+    pub(crate) fn new(max_size: usize) -> Result<Self, String> {
+        if max_size == 0 {
+            return Err("The mempool should be able to hold at least one transaction".to_string());
+        }
+        Ok(Self {
+            max_size,
+            order: VecDeque::new(),
+            transactions: HashMap::new(),
+        })
+    }
+
+    /// Returns the id of the transaction, unless it is already pending
+    pub(crate) fn check_not_pending(&self, transaction: &Transaction) -> Result<String, String> {
+        let id = transaction.id();
+        if self.transactions.contains_key(&id) {
+            return Err(format!("Already pending transaction {}: {:?}", id, transaction));
+        }
+        Ok(id)
+    }
+
+    /// Adds a transaction, evicting the oldest pending one if we are full.
+    /// Returns the id of the inserted transaction, and the one of the evicted transaction if any.
+    /// -- NOTE: When the oldest one is a transfer, its sender's newest transfer is evicted instead,
+    ///          so that its remaining transfers keep consecutive nonces. For the same reason, a new transfer
+    ///          from that sender is refused rather than queued behind the evicted one.
+    pub(crate) fn insert(&mut self, transaction: Transaction) -> Result<(String, Option<String>), String> {
+        let id = self.check_not_pending(&transaction)?;
+        let evicted_id = if self.order.len() >= self.max_size {
+            let evicted_id = self.eviction_candidate();
+            if let (Transaction::Transfer(evicted), Transaction::Transfer(transfer)) = (&self.transactions[&evicted_id], &transaction) {
+                if evicted.sender == transfer.sender {
+                    return Err(format!("The mempool is full, and {} has its oldest transactions pending: cannot send {} to {}",
+                                       transfer.sender, transfer.balance, transfer.receiver));
+                }
+            }
+            self.remove(&evicted_id);
+            Some(evicted_id)
+        } else {
+            None
+        };
+        self.order.push_back(id.clone());
+        self.transactions.insert(id.clone(), transaction);
+        Ok((id, evicted_id))
+    }
+
+    /// The oldest pending transaction, or the newest transfer of its sender when it is a transfer
+    fn eviction_candidate(&self) -> String {
+        let oldest_id = self.order.front().expect("A full mempool is not empty");
+        let Transaction::Transfer(oldest) = &self.transactions[oldest_id] else {
+            return oldest_id.clone();
+        };
+        self.order.iter().rev()
+            .find(|id| matches!(&self.transactions[*id], Transaction::Transfer(transfer) if transfer.sender == oldest.sender))
+            .expect("The oldest transfer is from that sender")
+            .clone()
+    }
+
+    /// Takes the transaction out of the pool, typically once it is included in a block
+    pub(crate) fn remove(&mut self, id: &str) -> Option<Transaction> {
+        let transaction = self.transactions.remove(id)?;
+        self.order.retain(|pending_id| pending_id != id);
+        Some(transaction)
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<&Transaction> {
+        self.transactions.get(id)
+    }
+
+    /// Total amount and number of the pending transfers sent by `name` that can still be mined:
+    /// the ones with consecutive nonces from its `confirmed_nonce`, the next ones waiting on a missing nonce.
+    pub(crate) fn pending_outgoing(&self, name: &str, confirmed_nonce: u64) -> (u64, u64) {
+        let amounts = self.transfers()
+            .filter(|transfer| transfer.sender == name)
+            .map(|transfer| (transfer.nonce, transfer.balance))
+            .collect::<HashMap<_, _>>();
+        (confirmed_nonce..)
+            .map_while(|nonce| amounts.get(&nonce))
+            .fold((0, 0), |(amount, count), balance| (amount.saturating_add(*balance), count + 1))
+    }
+
+    /// Total amount of the pending transfers received by `name`, capped at `u64::MAX`
+    pub(crate) fn pending_incoming(&self, name: &str) -> u64 {
+        self.transfers()
+            .filter(|transfer| transfer.receiver == name)
+            .fold(0, |amount: u64, transfer| amount.saturating_add(transfer.balance))
+    }
+
+    pub(crate) fn is_account_creation_pending(&self, name: &str) -> bool {
+        self.pending_account_creation_balance(name).is_some()
+    }
+
+    /// Starting balance of the account of `name`, if its creation is pending
+    pub(crate) fn pending_account_creation_balance(&self, name: &str) -> Option<u64> {
+        self.transactions.values().find_map(|transaction| match transaction {
+            Transaction::CreateAccount { name: pending_name, balance, .. } if pending_name == name => Some(*balance),
+            _ => None,
+        })
+    }
+
+    fn transfers(&self) -> impl Iterator<Item=&TransactionTransfer> {
+        self.transactions.values().filter_map(|transaction| match transaction {
+            Transaction::Transfer(transfer) => Some(transfer),
+            _ => None,
+        })
+    }
+
+    /// Pending transactions with their ids, oldest first
+    pub(crate) fn iter(&self) -> impl Iterator<Item=(&String, &Transaction)> {
+        self.order.iter().map(|id| (id, &self.transactions[id]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(sender: &str, balance: u64, nonce: u64) -> Transaction {
+        Transaction::Transfer(TransactionTransfer {
+            sender: sender.to_string(),
+            receiver: "bob".to_string(),
+            balance,
+            nonce,
+            signature: String::new(),
+        })
+    }
+
+    fn create_account(name: &str) -> Transaction {
+        Transaction::CreateAccount { name: name.to_string(), balance: 1000, public_key: None }
+    }
+
+    fn mempool(max_size: usize, transactions: impl IntoIterator<Item=Transaction>) -> Mempool {
+        let mut mempool = Mempool::new(max_size).expect("The mempool size is positive");
+        for transaction in transactions {
+            mempool.insert(transaction).expect("The transaction should be inserted");
+        }
+        mempool
+    }
+
+    fn pending_ids(mempool: &Mempool) -> Vec<String> {
+        mempool.iter().map(|(id, _)| id.clone()).collect()
+    }
+
+    #[test]
+    fn transactions_are_pending_once_in_insertion_order() {
+        let mut mempool = mempool(10, [create_account("alice"), transfer("alice", 10, 0)]);
+
+        let duplicate = mempool.insert(create_account("alice"));
+
+        assert!(matches!(&duplicate, Err(msg) if msg.starts_with("Already pending transaction")), "Got {:?}", duplicate);
+        assert_eq!(pending_ids(&mempool), [create_account("alice").id(), transfer("alice", 10, 0).id()]);
+        assert_eq!(mempool.get(&create_account("alice").id()).map(Transaction::id), Some(create_account("alice").id()));
+    }
+
+    #[test]
+    fn removed_transactions_are_not_pending_anymore() {
+        let mut mempool = mempool(10, [create_account("alice"), create_account("carol")]);
+
+        let removed = mempool.remove(&create_account("alice").id());
+        let removed_again = mempool.remove(&create_account("alice").id());
+
+        assert!(removed.is_some());
+        assert!(removed_again.is_none());
+        assert_eq!(pending_ids(&mempool), [create_account("carol").id()]);
+        assert!(mempool.insert(create_account("alice")).is_ok());
+    }
+
+    #[test]
+    fn a_full_mempool_evicts_the_oldest_transaction() {
+        let mut mempool = mempool(2, [create_account("alice"), create_account("carol")]);
+
+        let inserted = mempool.insert(create_account("dave"));
+
+        assert_eq!(inserted, Ok((create_account("dave").id(), Some(create_account("alice").id()))));
+        assert_eq!(pending_ids(&mempool), [create_account("carol").id(), create_account("dave").id()]);
+    }
+
+    #[test]
+    fn a_full_mempool_evicts_the_newest_transfer_of_the_oldest_sender() {
+        let mut mempool = mempool(3, (0..3).map(|nonce| transfer("alice", 10, nonce)));
+
+        let inserted = mempool.insert(create_account("dave"));
+        let refused = mempool.insert(transfer("alice", 10, 2));
+
+        assert_eq!(inserted, Ok((create_account("dave").id(), Some(transfer("alice", 10, 2).id()))));
+        assert!(matches!(&refused, Err(msg) if msg.starts_with("The mempool is full")), "Got {:?}", refused);
+        assert_eq!(mempool.pending_outgoing("alice", 0), (20, 2));
+    }
+
+    #[test]
+    fn pending_outgoing_transfers_stop_at_the_first_missing_nonce() {
+        let mempool = mempool(10, [transfer("alice", 10, 0), transfer("alice", 20, 1), transfer("alice", 40, 3)]);
+
+        assert_eq!(mempool.pending_outgoing("alice", 0), (30, 2));
+        assert_eq!(mempool.pending_outgoing("alice", 1), (20, 1));
+        assert_eq!(mempool.pending_outgoing("alice", 2), (0, 0));
+        assert_eq!(mempool.pending_outgoing("bob", 0), (0, 0));
+    }
+}
@@ -0,0 +1,541 @@
+use std::io::{BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use interprocess::local_socket::{GenericFilePath, ListenerOptions, prelude::*};
+
+use crate::block_chain::{self, BlockChain};
+use crate::client::{self, NodeAddress, NodeClient};
+use crate::clock::{Clock, SystemClock};
+use crate::genesis::Genesis;
+use crate::mempool::DEFAULT_MEMPOOL_SIZE;
+use crate::protocol::{self, Answer, ErrorCode, ProtocolError, Request, Response};
+use crate::storage::ChainStore;
+use crate::http_api::{self, HttpServer};
+use crate::{json_rpc, Commands, Transaction, TransactionTransfer};
+
+/// How long the node waits on a silent client, before dropping its connection
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many connections are served at once, unless `NodeBuilder::max_connections` says otherwise
+pub const DEFAULT_MAX_CONNECTIONS: usize = 128;
+
+/// Configures a node, then starts it in-process with `start`.
+/// -- NOTE: By default it only listens on a free port of localhost, and keeps its blocks in memory,
+///          so that several embedded nodes don't get in each other's way.
+#[derive(Debug)]
+pub struct NodeBuilder {
+    listen: String,
+    listen_socket: Option<PathBuf>,
+    http: Option<String>,
+    block_time: Option<Duration>,
+    data_dir: Option<PathBuf>,
+    genesis: Option<PathBuf>,
+    mempool_size: usize,
+    max_connections: usize,
+    clock: Box<dyn Clock>,
+}
+
+impl Default for NodeBuilder {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:0".to_string(),
+            listen_socket: None,
+            http: None,
+            block_time: None,
+            data_dir: None,
+            genesis: None,
+            mempool_size: DEFAULT_MEMPOOL_SIZE,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            clock: Box::new(SystemClock),
+        }
+    }
+}
+
+impl NodeBuilder {
+    /// Address the node accepts the client commands on, port 0 picking a free one
+    pub fn listen(mut self, addr: impl Into<String>) -> Self {
+        self.listen = addr.into();
+        self
+    }
+
+    /// Path of a local socket the node also accepts the client commands on
+    pub fn listen_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.listen_socket = Some(path.into());
+        self
+    }
+
+    /// Address of the REST API, which is only served when given
+    pub fn http(mut self, addr: impl Into<String>) -> Self {
+        self.http = Some(addr.into());
+        self
+    }
+
+    /// Time between each block, overriding the genesis one (default 10s, at most a day)
+    pub fn block_time(mut self, block_time: Duration) -> Self {
+        self.block_time = Some(block_time);
+        self
+    }
+
+    /// Directory where the sealed blocks are persisted, and restored from on start
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(data_dir.into());
+        self
+    }
+
+    /// Json file defining the chain id, block time and initial accounts, sealed as block 0
+    pub fn genesis(mut self, path: impl Into<PathBuf>) -> Self {
+        self.genesis = Some(path.into());
+        self
+    }
+
+    /// How many transfers can wait for the next block, the oldest ones being evicted first.
+    /// `start` fails if it is 0.
+    pub fn mempool_size(mut self, mempool_size: usize) -> Self {
+        self.mempool_size = mempool_size;
+        self
+    }
+
+    /// How many connections are served at once, across every transport, the extra ones being refused.
+    /// -- NOTE: Each `watch` holds its connection for as long as it watches.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Where the block times and timestamps come from (default the system clock)
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Restores the chain, binds every address, then mines and serves the clients on background threads.
+    /// -- NOTE: Everything that can fail is done before the mining thread starts,
+    ///          so an error leaves nothing running behind.
+    pub fn start(self) -> Result<Node, String> {
+        let genesis = self.genesis.as_deref().map(Genesis::load).transpose()?;
+        let block_time = self.block_time
+            .or_else(|| genesis.as_ref().and_then(|genesis| genesis.block_time))
+            .unwrap_or(block_chain::DEFAULT_BLOCK_TIME);
+        block_chain::check_block_time(block_time)?;
+        let mut block_chain = BlockChain::with_clock(block_time, self.clock);
+        if let Some(data_dir) = &self.data_dir {
+            block_chain = block_chain.restored_from(ChainStore::open(data_dir)?)?;
+        }
+        if let Some(genesis) = &genesis {
+            block_chain.start_from_genesis(genesis)?;
+        }
+        block_chain.limit_mempool(self.mempool_size)?;
+
+        let listener = TcpListener::bind(&self.listen)
+            .map_err(|e| format!("Could not listen on {}: {}", self.listen, e))?;
+        let address = listener.local_addr()
+            .map_err(|e| format!("Could not get the address bound for {}: {}", self.listen, e))?;
+        let local_listener = self.listen_socket.as_deref().map(bind_local_socket).transpose()?;
+        // NOTE: We could have reused Commands::Transfer, but that could be bad "de-duplication"
+        // as these data structures don't serve the same purpose and could diverge in later development.
+        let (transactions_tx, transactions_rx) = mpsc::channel();
+        let connections = ConnectionLimit::new(self.max_connections);
+        // NOTE: The requests wait in the channel until the mining thread starts, right after
+        let http = self.http.as_deref()
+            .map(|http_addr| http_api::serve(http_addr, transactions_tx.clone(), connections.clone()))
+            .transpose()?;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mining_thread = {
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || block_chain.run_until(transactions_rx, &stopped))
+        };
+
+        let local_accept_thread = local_listener.map(|local_listener| {
+            let transactions_tx = transactions_tx.clone();
+            let connections = connections.clone();
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || {
+                for stream in local_listener.incoming().flatten() {
+                    if stopped.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    match client::local_stream_with_timeouts(stream, Some(CONNECTION_TIMEOUT), Some(CONNECTION_TIMEOUT)) {
+                        Ok(stream) => spawn_connection(stream, &transactions_tx, &connections),
+                        Err(e) => log::warn!("Couldn't set the timeouts of a local connection: {}", e),
+                    }
+                }
+            })
+        });
+
+        let accept_thread = {
+            let transactions_tx = transactions_tx.clone();
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || while !stopped.load(Ordering::SeqCst) {
+                if let Ok((stream, _addr)) = listener.accept() {
+                    if stopped.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let timeouts = stream.set_read_timeout(Some(CONNECTION_TIMEOUT))
+                        .and_then(|_| stream.set_write_timeout(Some(CONNECTION_TIMEOUT)));
+                    match timeouts {
+                        Ok(_) => spawn_connection(stream, &transactions_tx, &connections),
+                        Err(e) => log::warn!("Couldn't set the timeouts of a connection: {}", e),
+                    }
+                }
+            })
+        };
+        Ok(Node {
+            address,
+            listen_socket: self.listen_socket,
+            http,
+            transactions_tx,
+            stopped,
+            accept_thread: Some(accept_thread),
+            local_accept_thread,
+            mining_thread: Some(mining_thread),
+        })
+    }
+}
+
+/// A node running in this process, mining and serving its clients on background threads.
+/// -- NOTE: Dropping it shuts it down, like `shutdown` does.
+#[derive(Debug)]
+pub struct Node {
+    address: SocketAddr,
+    listen_socket: Option<PathBuf>,
+    http: Option<HttpServer>,
+    /// Only used to wake the mining thread up on `shutdown`
+    transactions_tx: mpsc::Sender<(mpsc::Sender<Answer>, Transaction)>,
+    stopped: Arc<AtomicBool>,
+    /// The threads are only taken out of their `Option` to be joined
+    accept_thread: Option<JoinHandle<()>>,
+    /// Only there with a `listen_socket`, it holds the local socket until it is joined
+    local_accept_thread: Option<JoinHandle<()>>,
+    mining_thread: Option<JoinHandle<()>>,
+}
+
+impl Node {
+    pub fn builder() -> NodeBuilder {
+        NodeBuilder::default()
+    }
+
+    /// The address actually bound, with the port picked by the OS when asked for port 0
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The address actually bound by the REST API, if it is served
+    pub fn http_address(&self) -> Option<SocketAddr> {
+        self.http.as_ref().map(|http| http.addr)
+    }
+
+    /// A client sending its commands to this node over TCP
+    pub fn client(&self) -> NodeClient {
+        NodeClient::new(NodeAddress::Tcp(self.address.to_string()))
+    }
+
+    /// Blocks the calling thread for as long as the node accepts connections, which is until its `shutdown`
+    pub fn wait(mut self) {
+        if self.accept_thread.take().is_some_and(|thread| thread.join().is_err()) {
+            log::error!("The node stopped accepting connections");
+        }
+    }
+
+    /// Stops accepting connections and sealing blocks, and returns once every accepting thread let go of its listener
+    /// and the mining thread of the chain, so that another node can take over its address, local socket and data dir.
+    /// -- NOTE: The connections still being served get a `NodeUnavailable` error, and the `watch` streams end.
+    pub fn shutdown(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // NOTE: Every loop only sees `stopped` once woken up, by a connection or a request of our own
+        let _ = TcpStream::connect(wake_up_address(self.address));
+        if let Some(path) = &self.listen_socket {
+            let _ = path.as_path().to_fs_name::<GenericFilePath>().and_then(LocalSocketStream::connect);
+        }
+        if let Some(http) = self.http.take() {
+            http.stop();
+        }
+        let _ = self.transactions_tx.send((mpsc::channel().0, Transaction::LatestBlock));
+        let threads = [self.accept_thread.take(), self.local_accept_thread.take(), self.mining_thread.take()];
+        // NOTE: Every thread is joined, even after one of them failed
+        let failed = threads.into_iter().flatten().map(JoinHandle::join).filter(Result::is_err).count();
+        if failed > 0 {
+            log::error!("The node did not stop cleanly");
+        }
+    }
+}
+
+/// Where to connect to reach a listener, which can't be its unspecified address, like `0.0.0.0`
+pub(crate) fn wake_up_address(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), address.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), address.port()),
+        _ => address,
+    }
+}
+
+/// Counts the connections being served, so that a flood of them can't start one thread each
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionLimit {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// A connection being served, which gives its place back when dropped
+pub(crate) struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionLimit {
+    fn new(max: usize) -> Self {
+        Self { active: Arc::new(AtomicUsize::new(0)), max }
+    }
+
+    /// Takes a place for a new connection, or `None` when the limit is reached
+    pub(crate) fn acquire(&self) -> Option<ConnectionSlot> {
+        if self.active.fetch_add(1, Ordering::SeqCst) >= self.max {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(ConnectionSlot(Arc::clone(&self.active)))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves the connection on its own thread, so that a slow client doesn't stall the other ones,
+/// or refuses it right away when too many connections are already being served.
+/// -- NOTE: The BlockChain is still only touched by the mining thread, every request goes through its channel.
+fn spawn_connection<S: Read + Write + Send + 'static>(
+    mut stream: S,
+    transactions_tx: &mpsc::Sender<(mpsc::Sender<Answer>, Transaction)>,
+    connections: &ConnectionLimit,
+) {
+    let Some(slot) = connections.acquire() else {
+        // NOTE: Without reading the request, so that an idle client can't stall the accepting thread
+        let refusal = Response::Error(ProtocolError::new(ErrorCode::NodeUnavailable,
+                                                         "Too many connections, try again later"));
+        let line = serde_json::to_string(&refusal).expect("Our responses should always serialize") + "\n";
+        let _ = stream.write_all(line.as_bytes());
+        return;
+    };
+    let transactions_tx = transactions_tx.clone();
+    thread::spawn(move || {
+        serve_connection(stream, &transactions_tx);
+        drop(slot);
+    });
+}
+
+/// Listens on the local socket at `path`, taking over the file left behind by a node that was killed
+fn bind_local_socket(path: &Path) -> Result<LocalSocketListener, String> {
+    let name = || path.to_fs_name::<GenericFilePath>()
+        .map_err(|e| format!("Invalid local socket path {}: {}", path.display(), e));
+    // NOTE: A live node answers on its socket, and we must not steal it. Otherwise the file is stale.
+    if path.exists() && LocalSocketStream::connect(name()?).is_err() {
+        std::fs::remove_file(path)
+            .map_err(|e| format!("Could not remove the stale local socket {}: {}", path.display(), e))?;
+    }
+    ListenerOptions::new().name(name()?).create_sync()
+        .map_err(|e| format!("Could not listen on the local socket {}: {}", path.display(), e))
+}
+
+/// Answers the single request sent on this connection, whatever its transport.
+/// -- NOTE: A malformed request gets an error response, it must never take the node down.
+fn serve_connection<S: Read + Write>(stream: S, transactions_tx: &mpsc::Sender<(mpsc::Sender<Answer>, Transaction)>) {
+    let mut reader = BufReader::new(stream);
+    let line = match protocol::read_request_line(&mut reader) {
+        Ok(Some(line)) => line,
+        Ok(None) => return,
+        Err(e) => {
+            log::warn!("Couldn't read a request: {}", e);
+            return;
+        }
+    };
+    let response = if json_rpc::is_json_rpc(&line) {
+        match json_rpc::handle(&line, |transaction| submit(transactions_tx, transaction)) {
+            Some(response) => response + "\n",
+            None => return,
+        }
+    } else {
+        let response = match protocol::parse_request(&line) {
+            Ok(Request { command: Commands::Watch { accounts } }) => {
+                return stream_events(reader.get_mut(), transactions_tx, accounts);
+            }
+            Ok(request) => process_remote_command(transactions_tx, request.command),
+            Err(error) => Response::Error(error),
+        };
+        serde_json::to_string(&response).expect("Our responses should always serialize") + "\n"
+    };
+    if let Err(e) = reader.get_mut().write_all(response.as_bytes()) {
+        log::warn!("Couldn't respond: {} because {}", response, e);
+    }
+}
+
+/// Writes a response line for every block sealed from now on, until the client goes away
+fn stream_events<W: Write>(
+    writer: &mut W,
+    transactions_tx: &mpsc::Sender<(mpsc::Sender<Answer>, Transaction)>,
+    accounts: Vec<String>,
+) {
+    let (msg_tx, msg_rx) = mpsc::channel();
+    let subscribed = transactions_tx.send((msg_tx, Transaction::Subscribe { accounts }));
+    let mut responses = msg_rx.iter().map(Response::Ok);
+    let first_response = match subscribed {
+        Ok(()) => responses.next(),
+        Err(_) => None,
+    }.unwrap_or_else(|| Response::Error(ProtocolError::new(ErrorCode::NodeUnavailable,
+                                                             "The node is not processing transactions anymore")));
+    for response in std::iter::once(first_response).chain(responses) {
+        let line = serde_json::to_string(&response).expect("Our responses should always serialize") + "\n";
+        // NOTE: Dropping our receiver unsubscribes us, on the next block
+        if let Err(e) = writer.write_all(line.as_bytes()) {
+            log::info!("Stopped streaming to a watching client: {}", e);
+            return;
+        }
+    }
+}
+
+fn process_remote_command(transactions_tx: &mpsc::Sender<(mpsc::Sender<Answer>, Transaction)>, command: Commands) -> Response {
+    let transaction = match command {
+        Commands::StartNode { .. } => {
+            return Response::Error(ProtocolError::new(ErrorCode::UnsupportedCommand,
+                                                      "We don't allow restarting the node remotely"));
+        }
+        Commands::Keygen { .. } => {
+            return Response::Error(ProtocolError::new(ErrorCode::UnsupportedCommand,
+                                                      "Keys are generated locally, never by the node"));
+        }
+        Commands::CreateAccount { name, balance, public_key } => Transaction::CreateAccount {
+            name,
+            balance,
+            public_key,
+        },
+        Commands::Balance { name, pending } => Transaction::Balance {
+            name,
+            pending,
+        },
+        Commands::Transfer { sender, receiver, balance, nonce, key: _, signature } => Transaction::Transfer(TransactionTransfer {
+            sender,
+            receiver,
+            balance,
+            nonce,
+            signature,
+        }),
+        Commands::Nonce { name } => Transaction::Nonce {
+            name,
+        },
+        Commands::TxStatus { id } => Transaction::TxStatus {
+            id,
+        },
+        Commands::GetBlock { block } => Transaction::GetBlock {
+            block,
+        },
+        Commands::LatestBlock => Transaction::LatestBlock,
+        Commands::ListBlocks { from, to } => Transaction::ListBlocks {
+            from,
+            to,
+        },
+        Commands::History { name, page, per_page } => Transaction::History {
+            name,
+            page,
+            per_page,
+        },
+        Commands::Mempool => Transaction::Mempool,
+        Commands::Watch { .. } => {
+            return Response::Error(ProtocolError::new(ErrorCode::UnsupportedCommand,
+                                                      "Subscriptions are only streamed on their own connection"));
+        }
+        Commands::VerifyChain { .. } => Transaction::VerifyChain,
+    };
+    Response::from(submit(transactions_tx, transaction))
+}
+
+/// Hands the transaction (or query) to the mining thread, and waits for its answer
+pub(crate) fn submit(transactions_tx: &mpsc::Sender<(mpsc::Sender<Answer>, Transaction)>, transaction: Transaction) -> Result<Answer, ProtocolError> {
+    let (msg_tx, msg_rx) = mpsc::channel();
+    // NOTE: These only fail once the mining thread is gone, the client gets told instead of us panicking
+    let node_unavailable = || ProtocolError::new(ErrorCode::NodeUnavailable,
+                                                 "The node is not processing transactions anymore");
+    transactions_tx.send((msg_tx, transaction)).map_err(|_| node_unavailable())?;
+    msg_rx.recv().map_err(|_| node_unavailable())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_embedded_node_serves_its_clients() {
+        let node = Node::builder().block_time(Duration::from_millis(100)).start().expect("The node should start");
+        let client = node.client();
+
+        let created = client.request(&Commands::CreateAccount { name: "alice".to_string(), balance: 1000, public_key: None });
+        let balance = (0..20).find_map(|_| {
+            thread::sleep(Duration::from_millis(100));
+            match client.request(&Commands::Balance { name: "alice".to_string(), pending: false }) {
+                Ok(Answer::Balance { balance, .. }) => Some(balance),
+                _ => None,
+            }
+        });
+
+        assert!(matches!(created, Ok(Answer::AccountCreationAccepted { .. })), "Got {:?}", created);
+        assert_eq!(balance, Some(1000));
+        assert_ne!(node.address().port(), 0);
+    }
+
+    #[test]
+    fn a_node_does_not_start_on_a_busy_address() {
+        let node = Node::builder().start().expect("The node should start");
+
+        let error = Node::builder().listen(node.address().to_string()).start()
+            .expect_err("The address is already in use");
+
+        assert!(error.contains(&node.address().to_string()), "Got {}", error);
+    }
+    #[test]
+    fn a_node_does_not_start_with_an_empty_mempool() {
+        let error = Node::builder().mempool_size(0).start().expect_err("The mempool should hold a transaction");
+
+        assert!(error.contains("at least one transaction"), "Got {}", error);
+    }
+
+    #[test]
+    fn a_shut_down_node_lets_another_one_take_its_address() {
+        let node = Node::builder().start().expect("The node should start");
+        let address = node.address();
+
+        node.shutdown();
+        let restarted = Node::builder().listen(address.to_string()).start();
+
+        assert!(restarted.is_ok(), "Got {:?}", restarted);
+    }
+
+    #[test]
+    fn a_dropped_node_stops_serving() {
+        let node = Node::builder().start().expect("The node should start");
+        let client = node.client();
+
+        drop(node);
+
+        assert!(client.request(&Commands::LatestBlock).is_err());
+    }
+
+    #[test]
+    fn a_shut_down_node_lets_another_one_take_its_http_address() {
+        let node = Node::builder().http("127.0.0.1:0").start().expect("The node should start");
+        let http_address = node.http_address().expect("The REST API should be served");
+
+        node.shutdown();
+        let restarted = Node::builder().http(http_address.to_string()).start();
+
+        assert!(restarted.is_ok(), "Got {:?}", restarted);
+    }
+}
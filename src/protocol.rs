@@ -0,0 +1,292 @@
+use std::fmt;
+use std::io::{BufRead, Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::block_chain::{Block, HistoryEntry};
+use crate::mempool::PendingTransaction;
+use crate::{Commands, Transaction, TransactionTransfer};
+
+/// Longest request line the node reads, so that a client can't make it buffer without end
+pub const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// What a client sends to the node, as a single json line
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Request {
+    pub command: Commands,
+}
+
+/// What the node answers to each request, as a single json line
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    /// The request was processed, this is the node answer (which can still be a refusal, like a too low balance)
+    Ok(Answer),
+    /// The request could not be processed at all
+    Error(ProtocolError),
+}
+
+impl From<Result<Answer, ProtocolError>> for Response {
+    fn from(result: Result<Answer, ProtocolError>) -> Self {
+        match result {
+            Ok(answer) => Response::Ok(answer),
+            Err(error) => Response::Error(error),
+        }
+    }
+}
+
+/// Everything the node can answer to a command it processed.
+/// -- NOTE: Its `Display` is the human readable answer, its json is meant for scripts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Answer {
+    AccountCreationAccepted {
+        name: String,
+        balance: u64,
+        id: String,
+    },
+    TransferAccepted {
+        transfer: TransactionTransfer,
+        id: String,
+    },
+    /// The transaction won't be included in a block, `tx_status` will give the same reason
+    Rejected {
+        id: String,
+        reason: String,
+    },
+    Balance {
+        name: String,
+        balance: u64,
+        /// Only when asked for, the balance once the pending transfers are mined
+        projected_balance: Option<u64>,
+    },
+    Nonce {
+        name: String,
+        nonce: u64,
+    },
+    Account {
+        name: String,
+        balance: u64,
+        /// The balance once the pending transfers are mined
+        projected_balance: u64,
+        /// The nonce expected for its next transfer
+        nonce: u64,
+    },
+    AccountNotFound {
+        name: String,
+    },
+    TransactionStatus {
+        id: String,
+        status: TransactionStatus,
+        /// The transaction itself, while it is pending or once included (the rejected ones are not kept)
+        #[serde(default)]
+        transaction: Option<Transaction>,
+    },
+    Block(Block),
+    BlockNotFound {
+        block: String,
+    },
+    NoBlockYet,
+    Blocks(Vec<Block>),
+    History(Vec<HistoryEntry>),
+    Mempool(Vec<PendingTransaction>),
+    HistoryNotFound {
+        name: String,
+    },
+    ChainValid {
+        blocks: usize,
+    },
+    /// The first answer to `watch`, the next ones are streamed on the same connection as blocks get sealed
+    Subscribed {
+        accounts: Vec<String>,
+        latest_block: Option<usize>,
+    },
+    BlockSealed(Block),
+    AccountChanged {
+        name: String,
+        balance: u64,
+        nonce: u64,
+        block_num: usize,
+    },
+    ChainInvalid {
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TransactionStatus {
+    Included {
+        block_num: usize,
+    },
+    Pending,
+    Rejected {
+        reason: String,
+    },
+    Unknown,
+}
+
+impl fmt::Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Answer::AccountCreationAccepted { name, balance, id } =>
+                write!(f, "Will create the account of {} with balance {} in the next block, as transaction {}", name, balance, id),
+            Answer::TransferAccepted { transfer, id } =>
+                write!(f, "Will add this transaction in the next block: {:?}, as transaction {}", transfer, id),
+            Answer::Rejected { id, reason } => write!(f, "{}, as transaction {}", reason, id),
+            Answer::Balance { name, balance, projected_balance: None } =>
+                write!(f, "Account of {} has a balance of {}", name, balance),
+            Answer::Balance { name, balance, projected_balance: Some(projected_balance) } =>
+                write!(f, "Account of {} has a balance of {}, and a projected balance of {} after its pending transfers",
+                       name, balance, projected_balance),
+            Answer::Nonce { name, nonce } => write!(f, "Account of {} expects the nonce {} for its next transfer", name, nonce),
+            Answer::Account { name, balance, projected_balance, nonce } =>
+                write!(f, "Account of {} has a balance of {}, a projected balance of {}, and expects the nonce {}",
+                       name, balance, projected_balance, nonce),
+            Answer::AccountNotFound { name } => write!(f, "No account found for {}", name),
+            Answer::TransactionStatus { id, status, .. } => match status {
+                TransactionStatus::Included { block_num } => write!(f, "Transaction {} is included in block {}", id, block_num),
+                TransactionStatus::Pending => write!(f, "Transaction {} is pending, waiting for the next block", id),
+                TransactionStatus::Rejected { reason } => write!(f, "Transaction {} was rejected: {}", id, reason),
+                TransactionStatus::Unknown => write!(f, "Unknown transaction {}", id),
+            },
+            Answer::Block(block) => f.write_str(&to_json(block)),
+            Answer::BlockNotFound { block } => write!(f, "No block found for {}", block),
+            Answer::NoBlockYet => f.write_str("No block was mined yet"),
+            Answer::Blocks(blocks) => f.write_str(&to_json(blocks)),
+            Answer::History(entries) => f.write_str(&to_json(entries)),
+            Answer::Mempool(transactions) => f.write_str(&to_json(transactions)),
+            Answer::HistoryNotFound { name } => write!(f, "No history found for {}", name),
+            Answer::ChainValid { blocks } => write!(f, "All {} blocks of the chain are valid", blocks),
+            Answer::ChainInvalid { reason } => f.write_str(reason),
+            Answer::Subscribed { accounts, latest_block } => {
+                write!(f, "Watching the new blocks")?;
+                if !accounts.is_empty() {
+                    write!(f, " and the accounts of {}", accounts.join(", "))?;
+                }
+                match latest_block {
+                    Some(block_num) => write!(f, ", after block {}", block_num),
+                    None => write!(f, ", from the first one"),
+                }
+            }
+            Answer::BlockSealed(block) => write!(f, "Block {} sealed with {} transactions, hash {}",
+                                                 block.header.current_block_num, block.transactions.len(), block.header.hash),
+            Answer::AccountChanged { name, balance, nonce, block_num } =>
+                write!(f, "Account of {} has a balance of {} and expects the nonce {}, since block {}",
+                       name, balance, nonce, block_num),
+        }
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("Our blocks should always serialize")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request isn't a json serialized `Request`, or is too long
+    MalformedRequest,
+    /// The command is only meant to be run locally, like `start_node`
+    UnsupportedCommand,
+    /// The node is shutting down, or its mining thread died
+    NodeUnavailable,
+    /// Only reported by the client: the node could not be reached, or didn't answer a valid response
+    NodeUnreachable,
+    /// Only reported by the client: something failed before sending the command, like reading the key file
+    ClientFailure,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+/// Reads the next request line, without its trailing newline.
+/// Returns `Ok(None)` once the client closed the connection.
+pub(crate) fn read_request_line<R: BufRead>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    // NOTE: One more byte than allowed, to tell a request of exactly MAX_REQUEST_SIZE from a longer one
+    reader.by_ref().take(MAX_REQUEST_SIZE as u64 + 1).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Never panics, whatever the client sent us
+pub(crate) fn parse_request(line: &[u8]) -> Result<Request, ProtocolError> {
+    if line.len() > MAX_REQUEST_SIZE {
+        return Err(ProtocolError::new(ErrorCode::MalformedRequest,
+                                      format!("Requests should be at most {} bytes long", MAX_REQUEST_SIZE)));
+    }
+    serde_json::from_slice(line)
+        .map_err(|e| ProtocolError::new(ErrorCode::MalformedRequest, format!("Invalid request: {}", e)))
+}
+
+pub(crate) fn parse_response(line: &str) -> Result<Response, ProtocolError> {
+    serde_json::from_str(line)
+        .map_err(|e| ProtocolError::new(ErrorCode::NodeUnreachable, format!("Invalid response from the node: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn parsing_any_bytes_never_panics(line in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let _ = parse_request(&line);
+        }
+
+        #[test]
+        fn parsing_any_text_never_panics(line in "\\PC*") {
+            let _ = parse_request(line.as_bytes());
+        }
+
+        #[test]
+        fn parsing_json_looking_text_never_panics(line in r#"\{"command":\{"[A-Za-z]{0,12}":[\{\}\[\]",:0-9a-z-]{0,40}"#) {
+            let _ = parse_request(line.as_bytes());
+        }
+
+        #[test]
+        fn a_well_formed_request_is_parsed_back(name in "\\PC{0,40}", balance in any::<u64>()) {
+            let line = serde_json::to_vec(&Request { command: Commands::CreateAccount { name: name.clone(), balance, public_key: None } })
+                .expect("Requests should always serialize");
+            let parsed = parse_request(&line).map(|request| request.command);
+            let is_same_request = matches!(&parsed, Ok(Commands::CreateAccount { name: parsed_name, balance: parsed_balance, public_key: None })
+                if *parsed_name == name && *parsed_balance == balance);
+            prop_assert!(is_same_request, "Parsed back as {:?}", parsed);
+        }
+    }
+
+    #[test]
+    fn a_too_long_request_is_refused() {
+        let line = vec![b' '; MAX_REQUEST_SIZE + 10];
+        let mut reader = BufReader::new(&line[..]);
+        let read = read_request_line(&mut reader).expect("Reading from memory can't fail")
+            .expect("There is a request");
+
+        let error = parse_request(&read).expect_err("It should be too long");
+        assert_eq!(error.code, ErrorCode::MalformedRequest);
+    }
+
+    #[test]
+    fn a_request_line_stops_at_its_newline() {
+        let mut reader = BufReader::new(&b"{\"command\":\"LatestBlock\"}\ntrailing"[..]);
+        let read = read_request_line(&mut reader).expect("Reading from memory can't fail");
+
+        assert!(matches!(read.as_deref().map(parse_request), Some(Ok(Request { command: Commands::LatestBlock }))));
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::block_chain::Block;
+
+const BLOCKS_FILE_NAME: &str = "blocks.jsonl";
+
+/// Append-only store of the sealed blocks, one json serialized block per line.
+#[derive(Debug)]
+pub struct ChainStore {
+    blocks_path: PathBuf,
+    /// Set for the stores opened to be audited, which must be left exactly as they are
+    read_only: bool,
+}
+
+impl ChainStore {
+    /// Opens (and creates if needed) the store living in `data_dir`
+    pub fn open(data_dir: &Path) -> Result<Self, String> {
+        fs::create_dir_all(data_dir)
+            .map_err(|e| format!("Could not create data dir {}: {}", data_dir.display(), e))?;
+        Ok(Self { blocks_path: data_dir.join(BLOCKS_FILE_NAME), read_only: false })
+    }
+
+    /// Opens the store living in `data_dir` without ever writing to it, failing if it has no blocks file
+    pub fn open_read_only(data_dir: &Path) -> Result<Self, String> {
+        if !data_dir.is_dir() {
+            return Err(format!("No data dir at {}", data_dir.display()));
+        }
+        let blocks_path = data_dir.join(BLOCKS_FILE_NAME);
+        if !blocks_path.is_file() {
+            return Err(format!("No {} in the data dir {}", BLOCKS_FILE_NAME, data_dir.display()));
+        }
+        Ok(Self { blocks_path, read_only: true })
+    }
+
+    /// Reads back every block stored so far, in order
+    pub fn load(&self) -> Result<Vec<Block>, String> {
+        let file = match File::open(&self.blocks_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Could not open {}: {}", self.blocks_path.display(), e)),
+        };
+        let mut blocks = Vec::new();
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        let mut valid_len = 0;
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)
+                .map_err(|e| format!("Could not read {}: {}", self.blocks_path.display(), e))?;
+            if read == 0 {
+                break;
+            }
+            if !line.ends_with('\n') {
+                // NOTE: The node was killed while writing this block, so it was never acknowledged as sealed.
+                //       We cut it off, otherwise the next appended block would be glued to it.
+                //       A read-only store just ignores it, like the node restarting on it will.
+                if self.read_only {
+                    break;
+                }
+                log::warn!("Dropping a partially written block at the end of {}", self.blocks_path.display());
+                OpenOptions::new().write(true).open(&self.blocks_path)
+                    .and_then(|file| file.set_len(valid_len))
+                    .map_err(|e| format!("Could not truncate {}: {}", self.blocks_path.display(), e))?;
+                break;
+            }
+            blocks.push(serde_json::from_str(&line)
+                .map_err(|e| format!("Corrupted block #{} in {}: {}", blocks.len(), self.blocks_path.display(), e))?);
+            valid_len += read as u64;
+        }
+        Ok(blocks)
+    }
+
+    /// Durably appends a newly sealed block
+    pub(crate) fn append(&self, block: &Block) -> Result<(), String> {
+        if self.read_only {
+            return Err(format!("Cannot write to {}, it was opened read-only", self.blocks_path.display()));
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.blocks_path)
+            .map_err(|e| format!("Could not open {}: {}", self.blocks_path.display(), e))?;
+        let line = serde_json::to_string(block).expect("Our blocks should always serialize") + "\n";
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|e| format!("Could not write to {}: {}", self.blocks_path.display(), e))
+    }
+}
@@ -0,0 +1,224 @@
+use toy_blockchain_cli::node::Node;
+use toy_blockchain_cli::protocol::{Answer, TransactionStatus};
+use toy_blockchain_cli::{verify_stored_chain, Commands, Transaction};
+
+use common::{keygen, TempPath, TestNode, BLOCK_TIME};
+
+mod common;
+
+fn balance(node: &TestNode, name: &str) -> Answer {
+    node.request(Commands::Balance { name: name.to_string(), pending: false })
+}
+
+#[test]
+fn blocks_are_sealed_every_block_time() {
+    let node = TestNode::with_manual_clock(1_700_000_000_000);
+
+    let blocks = [node.seal_block(), node.seal_block(), node.seal_block()];
+
+    for pair in blocks.windows(2) {
+        assert_eq!(pair[0].header.current_block_num + 1, pair[1].header.current_block_num);
+        assert_eq!(pair[0].header.hash, pair[1].header.parent_hash);
+        assert_eq!(pair[0].header.timestamp + BLOCK_TIME.as_millis() as u64, pair[1].header.timestamp);
+    }
+}
+
+#[test]
+fn a_node_keeps_sealing_blocks_on_its_own() {
+    let node = TestNode::start();
+
+    let blocks = node.wait_for_blocks(3);
+
+    let block_nums = blocks.iter().map(|block| block.header.current_block_num).collect::<Vec<_>>();
+    assert_eq!(block_nums, [block_nums[0], block_nums[0] + 1, block_nums[0] + 2]);
+}
+
+#[test]
+fn account_creation_and_balance() {
+    let node = TestNode::start();
+
+    node.create_account("bob", 1000, None);
+    let second_creation = node.request(Commands::CreateAccount { name: "bob".to_string(), balance: 1000, public_key: None });
+
+    assert!(matches!(&second_creation, Answer::Rejected { reason, .. } if reason.contains("Already existing account")),
+            "Got {}", second_creation);
+}
+
+#[test]
+fn account_creation_and_already_being_created() {
+    let node = TestNode::start();
+
+    let balance_before = balance(&node, "bob");
+    node.create_account("bob", 1000, None);
+    let balance_after = balance(&node, "bob");
+
+    assert!(matches!(balance_before, Answer::AccountNotFound { .. }), "Got {}", balance_before);
+    assert!(matches!(balance_after, Answer::Balance { balance: 1000, .. }), "Got {}", balance_after);
+}
+
+#[test]
+fn transactions() {
+    let node = TestNode::start();
+    let key_paths = [TempPath::new("transactions-alice.key"), TempPath::new("transactions-bob.key")];
+    let public_keys = key_paths.each_ref().map(keygen);
+    node.create_account("alice", 1000, Some(public_keys[0].clone()));
+    node.create_account("bob", 9000, Some(public_keys[1].clone()));
+
+    let transfer = || node.transfer(&key_paths[0], "alice", "bob", 1000, 0);
+    let wrongly_signed_transfer = node.transfer(&key_paths[1], "alice", "bob", 1000, 0);
+    let accepted_transfer = transfer();
+    let duplicated_transfer = transfer();
+    let transfer_id = match &accepted_transfer {
+        Answer::TransferAccepted { id, .. } => id.clone(),
+        answer => panic!("The transfer should have been accepted, got {}", answer),
+    };
+    let pending_status = node.request(Commands::TxStatus { id: transfer_id.clone() });
+    let balances_before_block = [balance(&node, "alice"), balance(&node, "bob")];
+    let included_block_num = node.wait_until_included(&transfer_id);
+    let balances_after_block = [balance(&node, "alice"), balance(&node, "bob")];
+    let nonce = node.request(Commands::Nonce { name: "alice".to_string() });
+    let replayed_transfer = transfer();
+    let rejected_status = match &wrongly_signed_transfer {
+        Answer::Rejected { id, .. } => node.request(Commands::TxStatus { id: id.clone() }),
+        answer => panic!("The wrongly signed transfer should have been rejected, got {}", answer),
+    };
+    let history = node.request(Commands::History { name: "bob".to_string(), page: 1, per_page: 20 });
+    let second_history_page = node.request(Commands::History { name: "bob".to_string(), page: 2, per_page: 1 });
+
+    assert!(matches!(&wrongly_signed_transfer, Answer::Rejected { reason, .. } if reason.starts_with("Invalid signature")),
+            "Got {}", wrongly_signed_transfer);
+    assert!(matches!(&duplicated_transfer, Answer::Rejected { reason, .. } if reason.contains("Already pending transaction")),
+            "Got {}", duplicated_transfer);
+    assert!(matches!(&pending_status, Answer::TransactionStatus { status: TransactionStatus::Pending, .. }), "Got {}", pending_status);
+    assert!(matches!(balances_before_block, [Answer::Balance { balance: 1000, .. }, Answer::Balance { balance: 9000, .. }]));
+    assert!(matches!(balances_after_block, [Answer::Balance { balance: 0, .. }, Answer::Balance { balance: 10000, .. }]));
+    assert!(matches!(nonce, Answer::Nonce { nonce: 1, .. }), "Got {}", nonce);
+    assert!(matches!(&replayed_transfer, Answer::Rejected { reason, .. } if reason.contains("Out of order nonce 0")),
+            "Got {}", replayed_transfer);
+    assert!(matches!(&rejected_status, Answer::TransactionStatus { status: TransactionStatus::Rejected { reason }, .. }
+                     if reason.starts_with("Invalid signature")), "Got {}", rejected_status);
+    match history {
+        Answer::History(entries) => {
+            assert!(matches!(entries[0].transaction, Transaction::CreateAccount { .. }));
+            assert_eq!(entries[1].transaction_id, transfer_id);
+            assert_eq!(entries[1].block_num, included_block_num);
+        }
+        answer => panic!("Bob should have a history, got {}", answer),
+    }
+    match second_history_page {
+        Answer::History(entries) => {
+            assert_eq!(entries.len(), 1);
+            assert!(matches!(entries[0].transaction, Transaction::Transfer(_)));
+        }
+        answer => panic!("Bob should have a second history page, got {}", answer),
+    }
+}
+
+#[test]
+fn pending_transfers_count_against_the_sender_balance() {
+    let node = TestNode::with_manual_clock(1_700_000_000_000);
+    let key_path = TempPath::new("pending-alice.key");
+    let public_key = keygen(&key_path);
+    node.request(Commands::CreateAccount { name: "alice".to_string(), balance: 1000, public_key: Some(public_key) });
+    node.request(Commands::CreateAccount { name: "bob".to_string(), balance: 0, public_key: None });
+    node.seal_block();
+
+    let first_transfer = node.transfer(&key_path, "alice", "bob", 600, 0);
+    let overspending_transfer = node.transfer(&key_path, "alice", "bob", 600, 1);
+    let second_transfer = node.transfer(&key_path, "alice", "bob", 400, 1);
+    let pending_balance = node.request(Commands::Balance { name: "alice".to_string(), pending: true });
+
+    assert!(matches!(first_transfer, Answer::TransferAccepted { .. }), "Got {}", first_transfer);
+    assert!(matches!(&overspending_transfer, Answer::Rejected { reason, .. } if reason.contains("Insufficient funds")),
+            "Got {}", overspending_transfer);
+    assert!(matches!(second_transfer, Answer::TransferAccepted { .. }), "Got {}", second_transfer);
+    assert!(matches!(pending_balance, Answer::Balance { balance: 1000, projected_balance: Some(0), .. }), "Got {}", pending_balance);
+}
+
+#[test]
+fn blocks_can_be_queried_by_number_hash_and_range() {
+    let node = TestNode::start();
+    node.wait_for_blocks(3);
+
+    let first_block = node.request(Commands::GetBlock { block: "0".to_string() });
+    let first_block_hash = match &first_block {
+        Answer::Block(block) => block.header.hash.clone(),
+        answer => panic!("The first block should be sealed, got {}", answer),
+    };
+    let first_block_by_hash = node.request(Commands::GetBlock { block: first_block_hash });
+    let missing_block = node.request(Commands::GetBlock { block: "1000".to_string() });
+    let latest_block = node.request(Commands::LatestBlock);
+    let listed_blocks = node.request(Commands::ListBlocks { from: Some(0), to: Some(1) });
+
+    assert!(matches!(&first_block, Answer::Block(block) if block.transactions.is_empty()));
+    assert!(matches!(&first_block_by_hash, Answer::Block(block) if block.header.current_block_num == 0));
+    assert!(matches!(missing_block, Answer::BlockNotFound { .. }), "Got {}", missing_block);
+    assert!(matches!(&latest_block, Answer::Block(block) if block.header.current_block_num >= 2), "Got {}", latest_block);
+    match listed_blocks {
+        Answer::Blocks(blocks) => assert_eq!(blocks.iter().map(|block| block.header.current_block_num).collect::<Vec<_>>(), [0, 1]),
+        answer => panic!("The blocks should be listed, got {}", answer),
+    }
+}
+
+#[test]
+fn restarting_with_a_data_dir_keeps_the_accounts() {
+    let data_dir = TempPath::new("restart");
+    let start_node = || TestNode::start_with(Node::builder().data_dir(&data_dir.0), BLOCK_TIME);
+
+    let node = start_node();
+    node.create_account("bob", 1000, None);
+    node.shutdown();
+    let restarted_node = start_node();
+    let restarted_balance = balance(&restarted_node, "bob");
+
+    assert!(matches!(restarted_balance, Answer::Balance { balance: 1000, .. }), "Got {}", restarted_balance);
+}
+
+#[test]
+fn verify_chain_reports_the_first_tampered_block() {
+    let data_dir = TempPath::new("verify");
+    let node = TestNode::start_with(Node::builder().data_dir(&data_dir.0), BLOCK_TIME);
+    node.create_account("bob", 1000, None);
+    let node_verification = node.request(Commands::VerifyChain { data_dir: None });
+    node.shutdown();
+
+    let stored_verification = verify_stored_chain(&data_dir.0);
+    let blocks_path = data_dir.0.join("blocks.jsonl");
+    let blocks = std::fs::read_to_string(&blocks_path).expect("The blocks should be stored");
+    let tampered_block_num = blocks.lines().position(|line| line.contains("bob")).expect("Bob should be stored");
+    std::fs::write(&blocks_path, blocks.replace("\"balance\":1000", "\"balance\":9000")).expect("The blocks should be writable");
+    let tampered_verification = verify_stored_chain(&data_dir.0);
+
+    assert!(matches!(node_verification, Answer::ChainValid { .. }), "Got {}", node_verification);
+    assert!(matches!(stored_verification, Answer::ChainValid { .. }), "Got {}", stored_verification);
+    assert!(matches!(&tampered_verification, Answer::ChainInvalid { reason }
+                     if reason.contains(&format!("Block {} is invalid", tampered_block_num))), "Got {}", tampered_verification);
+}
+
+#[test]
+fn verify_chain_refuses_a_missing_data_dir() {
+    let data_dir = TempPath::new("verify-missing");
+
+    let verification = verify_stored_chain(&data_dir.0);
+
+    assert!(matches!(&verification, Answer::ChainInvalid { reason } if reason.contains("No data dir")), "Got {}", verification);
+    assert!(!data_dir.0.exists(), "Verifying should not create the data dir");
+}
+
+#[test]
+fn start_node_with_a_genesis_seals_its_accounts_as_block_0() {
+    let genesis_path = TempPath::new("genesis.json");
+    std::fs::write(&genesis_path.0, r#"{
+        "chain_id": "integration-tests",
+        "block_time": "500ms",
+        "accounts": [{ "name": "alice", "balance": 1000 }, { "name": "bob", "balance": 9000 }]
+    }"#).expect("The genesis should be writable");
+    let node = TestNode::start_with(Node::builder().genesis(&genesis_path.0), BLOCK_TIME);
+
+    let genesis_block = node.request(Commands::GetBlock { block: "0".to_string() });
+    let bob_balance = balance(&node, "bob");
+
+    assert!(matches!(&genesis_block, Answer::Block(block)
+                     if block.header.chain_id == "integration-tests" && block.transactions.len() == 2), "Got {}", genesis_block);
+    assert!(matches!(bob_balance, Answer::Balance { balance: 9000, .. }), "Got {}", bob_balance);
+}
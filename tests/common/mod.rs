@@ -0,0 +1,168 @@
+//! Starts nodes in-process for the integration tests, each one on its own free port,
+//! and follows their sealed blocks as events, so that the tests neither sleep nor get in each other's way.
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use toy_blockchain_cli::block_chain::Block;
+use toy_blockchain_cli::client::NodeClient;
+use toy_blockchain_cli::clock::ManualClock;
+use toy_blockchain_cli::keys;
+use toy_blockchain_cli::node::{Node, NodeBuilder};
+use toy_blockchain_cli::protocol::{Answer, TransactionStatus};
+use toy_blockchain_cli::Commands;
+
+/// Short enough for the tests to go fast, long enough for a few requests to land in the same block
+pub const BLOCK_TIME: Duration = Duration::from_millis(200);
+
+/// How long we wait for an event before failing the test, rather than hanging
+const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A node running in the test process, shut down when dropped
+pub struct TestNode {
+    node: Node,
+    client: NodeClient,
+    /// Everything the node streams to a `watch` on every account, from its subscription on
+    events: Receiver<Answer>,
+    /// Only set with `with_manual_clock`, the blocks are then sealed by `seal_block`
+    clock: Option<ManualClock>,
+    block_time: Duration,
+}
+
+impl TestNode {
+    /// A node sealing a block every `BLOCK_TIME`, without storage
+    pub fn start() -> Self {
+        Self::start_with(Node::builder(), BLOCK_TIME)
+    }
+
+    /// A node that only seals its blocks when told to, with `seal_block`, at exact timestamps
+    pub fn with_manual_clock(start_millis: u64) -> Self {
+        let clock = ManualClock::new(start_millis);
+        let mut test_node = Self::start_with(Node::builder().clock(clock.clone()), BLOCK_TIME);
+        test_node.clock = Some(clock);
+        test_node
+    }
+
+    /// Starts the node, and only returns once it confirmed our subscription, so that its mining thread is ready
+    pub fn start_with(builder: NodeBuilder, block_time: Duration) -> Self {
+        let node = builder.block_time(block_time).start().expect("The node should start");
+        let client = node.client();
+        let (events_tx, events) = mpsc::channel();
+        let watcher = client.clone();
+        thread::spawn(move || watcher.watch(&Commands::Watch { accounts: Vec::new() }, |answer| {
+            // NOTE: The test may be done with the events already
+            let _ = events_tx.send(answer);
+        }));
+        let test_node = Self { node, client, events, clock: None, block_time };
+        match test_node.next_event() {
+            Answer::Subscribed { .. } => test_node,
+            event => panic!("The node should first confirm the subscription, got {}", event),
+        }
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    pub fn address(&self) -> String {
+        self.node().address().to_string()
+    }
+
+    /// Sends the command to the node, failing the test if it can't be processed at all
+    pub fn request(&self, command: Commands) -> Answer {
+        self.client.request(&command).unwrap_or_else(|error| panic!("{:?} failed: {:?}", command, error))
+    }
+
+    /// The next block, account change or other event streamed by the node
+    pub fn next_event(&self) -> Answer {
+        self.events.recv_timeout(EVENT_TIMEOUT).expect("The node should keep streaming its events")
+    }
+
+    /// The next block sealed by the node, skipping the other events
+    pub fn next_block(&self) -> Block {
+        loop {
+            if let Answer::BlockSealed(block) = self.next_event() {
+                return block;
+            }
+        }
+    }
+
+    pub fn wait_for_blocks(&self, count: usize) -> Vec<Block> {
+        (0..count).map(|_| self.next_block()).collect()
+    }
+
+    /// Waits until the transaction is sealed, and returns the number of its block
+    pub fn wait_until_included(&self, id: &str) -> usize {
+        loop {
+            match self.request(Commands::TxStatus { id: id.to_string() }) {
+                Answer::TransactionStatus { status: TransactionStatus::Included { block_num }, .. } => return block_num,
+                Answer::TransactionStatus { status: TransactionStatus::Pending, .. } => {
+                    self.next_block();
+                }
+                answer => panic!("Transaction {} won't be included: {}", id, answer),
+            }
+        }
+    }
+
+    /// Sends a transfer signed with the key saved in `key_path`, like the `transfer` command does
+    pub fn transfer(&self, key_path: &TempPath, sender: &str, receiver: &str, balance: u64, nonce: u64) -> Answer {
+        let signing_key = keys::load_signing_key(&key_path.0).expect("The key should be readable");
+        self.client.transfer(&signing_key, sender, receiver, balance, nonce)
+            .unwrap_or_else(|error| panic!("The transfer from {} failed: {:?}", sender, error))
+    }
+
+    /// Creates the account and waits for it to be sealed
+    pub fn create_account(&self, name: &str, balance: u64, public_key: Option<String>) {
+        let id = accepted_id(self.request(Commands::CreateAccount { name: name.to_string(), balance, public_key }));
+        self.wait_until_included(&id);
+    }
+
+    /// Moves the manual clock a block time ahead, and returns the block then sealed
+    pub fn seal_block(&self) -> Block {
+        let clock = self.clock.as_ref().expect("Only the nodes with a manual clock seal their blocks on demand");
+        clock.advance(self.block_time);
+        // NOTE: The mining thread sleeps on its real time, any request wakes it up to look at the clock again
+        self.request(Commands::LatestBlock);
+        self.next_block()
+    }
+
+    /// Stops the node, so that another one can take over its address, local socket and data dir
+    pub fn shutdown(self) {
+        self.node.shutdown();
+    }
+}
+
+/// The id of an accepted transaction, failing the test otherwise
+pub fn accepted_id(answer: Answer) -> String {
+    match answer {
+        Answer::AccountCreationAccepted { id, .. } | Answer::TransferAccepted { id, .. } => id,
+        answer => panic!("The transaction should have been accepted, got {}", answer),
+    }
+}
+
+/// A path in the temp dir, unique to this test process, removed when dropped
+pub struct TempPath(pub PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("toy-blockchain-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A fresh keypair, its secret key being saved in `key_path`, returning its public key
+pub fn keygen(key_path: &TempPath) -> String {
+    keys::keygen(&key_path.0).expect("The key should be generated")
+}
@@ -0,0 +1,185 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+use toy_blockchain_cli::client::{NodeAddress, NodeClient};
+use toy_blockchain_cli::node::Node;
+use toy_blockchain_cli::protocol::{Answer, ErrorCode};
+use toy_blockchain_cli::Commands;
+
+use common::{accepted_id, TempPath, TestNode, BLOCK_TIME};
+
+mod common;
+
+/// Sends the raw bytes on a new connection, and returns the line answered
+fn send_raw(address: &str, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(address).expect("The node should accept connections");
+    stream.write_all(request).expect("The node should read our request");
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).expect("The node should answer");
+    response
+}
+
+#[test]
+fn an_idle_client_does_not_stall_the_other_ones() {
+    let node = TestNode::start();
+
+    let idle_client = TcpStream::connect(node.address()).expect("The node should accept connections");
+    let (answer_tx, answer_rx) = mpsc::channel();
+    let client = node.node().client();
+    thread::spawn(move || {
+        let _ = answer_tx.send(client.request(&Commands::Balance { name: "bob".to_string(), pending: false }));
+    });
+    // NOTE: A node serving one connection at a time would only answer once the idle one times out, after 5s
+    let balance = answer_rx.recv_timeout(Duration::from_secs(2)).expect("The node should answer without waiting");
+
+    drop(idle_client);
+    assert!(matches!(balance, Ok(Answer::AccountNotFound { .. })), "Got {:?}", balance);
+}
+
+#[test]
+fn a_client_gives_up_on_a_silent_node() {
+    // NOTE: The OS completes the connections, but nobody ever answers them
+    let silent_node = TcpListener::bind("127.0.0.1:0").expect("The OS should give us a free port");
+    let address = silent_node.local_addr().expect("We just bound it").to_string();
+    let client = NodeClient::new(NodeAddress::Tcp(address)).read_timeout(Some(Duration::from_millis(100)));
+
+    let answer = client.request(&Commands::LatestBlock);
+
+    assert!(matches!(&answer, Err(error) if error.code == ErrorCode::NodeUnreachable), "Got {:?}", answer);
+}
+
+#[test]
+fn connections_past_the_limit_are_refused() {
+    // NOTE: The harness own `watch` holds one of the 2 connections
+    let node = TestNode::start_with(Node::builder().max_connections(2), BLOCK_TIME);
+    let client = NodeClient::new(NodeAddress::Tcp(node.address()));
+
+    let idle_client = TcpStream::connect(node.address()).expect("The node should accept connections");
+    let refused = client.request(&Commands::LatestBlock);
+    drop(idle_client);
+    let served_again = (0..50).find_map(|_| {
+        thread::sleep(Duration::from_millis(20));
+        client.request(&Commands::LatestBlock).ok()
+    });
+
+    assert!(refused.is_err(), "Got {:?}", refused);
+    assert!(served_again.is_some(), "The node should serve new connections once the idle one is gone");
+}
+
+#[test]
+fn malformed_requests_get_an_error_without_killing_the_node() {
+    let node = TestNode::start();
+
+    let garbage_response = send_raw(&node.address(), b"{\"command\": not json\n");
+    let binary_response = send_raw(&node.address(), &[0xff, 0xfe, 0x00, b'\n']);
+    let start_node_response = send_raw(&node.address(),
+        b"{\"command\":{\"StartNode\":{\"listen\":\"127.0.0.1:0\",\"listen_socket\":null,\"http\":null,\"block_time\":null,\
+        \"data_dir\":null,\"genesis\":null,\"mempool_size\":1}}}\n");
+    let balance = node.request(Commands::Balance { name: "bob".to_string(), pending: false });
+
+    assert!(garbage_response.contains("MalformedRequest"), "Got {}", garbage_response);
+    assert!(binary_response.contains("MalformedRequest"), "Got {}", binary_response);
+    assert!(start_node_response.contains("UnsupportedCommand"), "Got {}", start_node_response);
+    assert!(matches!(balance, Answer::AccountNotFound { .. }), "Got {}", balance);
+}
+
+#[test]
+fn json_rpc_calls_are_answered_on_the_same_address() {
+    let node = TestNode::start();
+    let call = |request: &str| -> Value {
+        serde_json::from_str(&send_raw(&node.address(), (request.to_string() + "\n").as_bytes()))
+            .expect("The node should answer json")
+    };
+
+    let account_creation = call(r#"{"jsonrpc":"2.0","method":"createAccount","params":{"name":"bob","balance":1000},"id":1}"#);
+    node.wait_until_included(account_creation["result"]["id"].as_str().expect("The account creation should have an id"));
+    // NOTE: Like our own requests, a batch must fit on a single line
+    let batch = call(concat!(r#"[{"jsonrpc":"2.0","method":"getBalance","params":["bob"],"id":1},"#,
+                             r#"{"jsonrpc":"2.0","method":"getBlock","params":[0],"id":2}]"#));
+
+    assert_eq!(batch[0]["result"]["balance"], 1000);
+    assert_eq!(batch[1]["result"]["header"]["current_block_num"], 0);
+}
+
+#[test]
+fn the_rest_api_serves_accounts_and_transactions() {
+    let node = TestNode::start_with(Node::builder().http("127.0.0.1:0"), BLOCK_TIME);
+    let http = node.node().http_address().expect("The REST API should be served");
+    let http_request = |method: &str, path: &str, body: &str| -> String {
+        let mut stream = TcpStream::connect(http).expect("The HTTP server should accept connections");
+        write!(stream, "{} {} HTTP/1.0\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body)
+            .expect("The HTTP server should read our request");
+        let mut response = String::new();
+        stream.read_to_string(&mut response).expect("The HTTP server should answer");
+        response
+    };
+
+    let posted = http_request("POST", "/transactions", r#"{"CreateAccount":{"name":"bob","balance":1000,"public_key":null}}"#);
+    let mempool = http_request("GET", "/mempool", "");
+    let id = posted.split("\"id\":\"").nth(1).and_then(|id| id.split('"').next()).expect("The posted transaction should have an id");
+    node.wait_until_included(id);
+    let account = http_request("GET", "/accounts/bob", "");
+    let missing_block = http_request("GET", "/blocks/1000", "");
+
+    assert!(posted.starts_with("HTTP/1.0 202"), "Got {}", posted);
+    // NOTE: The account creation could already be sealed, so the mempool may be empty
+    assert!(mempool.starts_with("HTTP/1.0 200"), "Got {}", mempool);
+    assert!(account.starts_with("HTTP/1.0 200") && account.contains("\"balance\":1000"), "Got {}", account);
+    assert!(missing_block.starts_with("HTTP/1.0 404"), "Got {}", missing_block);
+}
+
+#[test]
+fn watch_streams_the_sealed_blocks_and_the_watched_account_changes() {
+    let node = TestNode::start();
+    let (events_tx, events) = mpsc::channel();
+    let client = node.node().client();
+    thread::spawn(move || client.watch(&Commands::Watch { accounts: vec!["bob".to_string()] }, |answer| {
+        let _ = events_tx.send(answer);
+    }));
+    let next_event = || events.recv_timeout(Duration::from_secs(10)).expect("The node should keep streaming the new blocks");
+
+    let subscribed = next_event();
+    for name in ["alice", "bob"] {
+        accepted_id(node.request(Commands::CreateAccount { name: name.to_string(), balance: 1000, public_key: None }));
+    }
+    let mut other_events = Vec::new();
+    let account_changed = loop {
+        match next_event() {
+            event @ Answer::AccountChanged { .. } => break event,
+            event => other_events.push(event),
+        }
+    };
+
+    assert!(matches!(subscribed, Answer::Subscribed { .. }), "Got {}", subscribed);
+    assert!(other_events.iter().all(|event| matches!(event, Answer::BlockSealed(_))), "Got {:?}", other_events);
+    // alice's creation is sealed before or with bob's one, but isn't watched
+    assert!(matches!(&account_changed, Answer::AccountChanged { name, balance: 1000, .. } if name == "bob"),
+            "Got {}", account_changed);
+}
+
+#[test]
+fn commands_can_go_through_a_local_socket() {
+    let socket = TempPath::new("node.sock");
+    let start_node = || TestNode::start_with(Node::builder().listen_socket(&socket.0), BLOCK_TIME);
+    let client = NodeClient::new(NodeAddress::LocalSocket(socket.0.clone()));
+    let balance = || client.request(&Commands::Balance { name: "bob".to_string(), pending: false });
+
+    let node = start_node();
+    let account_creation = client.request(&Commands::CreateAccount { name: "bob".to_string(), balance: 1000, public_key: None });
+    node.wait_until_included(&accepted_id(account_creation.expect("The local socket should be reachable")));
+    let socket_balance = balance();
+    node.shutdown();
+    let stopped_balance = balance();
+    let restarted_node = start_node();
+    let restarted_balance = balance();
+
+    assert!(matches!(socket_balance, Ok(Answer::Balance { balance: 1000, .. })), "Got {:?}", socket_balance);
+    assert!(matches!(&stopped_balance, Err(error) if error.code == ErrorCode::NodeUnreachable), "Got {:?}", stopped_balance);
+    // Without a data dir, the restarted node is a brand new chain
+    assert!(matches!(restarted_balance, Ok(Answer::AccountNotFound { .. })), "Got {:?}", restarted_balance);
+    drop(restarted_node);
+}